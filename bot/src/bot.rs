@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cgmath::Deg;
+use futures::{future, SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+use common::physics::{InputCommand, MoveDirection, INPUT_RATE};
+use common::protocol::{self, deserialize, serialize, RoomError, SessionId, PROTOCOL_VERSION};
+
+use crate::config::Config;
+use crate::report::BotReport;
+
+/// How long the bots wait for the answers still in flight once they stop sending.
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Inputs spent walking in the same direction before turning, the bots walk in squares.
+const LEG_INPUTS: u32 = 2 * INPUT_RATE;
+const LEGS: [MoveDirection; 4] = [MoveDirection::FORWARD, MoveDirection::RIGHT, MoveDirection::BACK, MoveDirection::LEFT];
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A scripted player, it connects, walks around and chats until `end`.
+pub struct Bot {
+    index: usize,
+    config: Arc<Config>,
+    report: BotReport,
+    session: Option<SessionId>,
+    /// Room the bot should play in
+    room: String,
+    /// The server confirmed the bot is in its room, it can start moving and chatting
+    playing: bool,
+    /// Clock the pings' timestamps are taken from
+    created: Instant,
+    /// Chat messages still waiting for their echo, by sequence
+    pending_chats: HashMap<u32, Instant>,
+    chat_sequence: u32,
+    input_sequence: u32,
+    /// Last snapshot received, acked with the inputs
+    last_snapshot: Option<u32>,
+}
+
+impl Bot {
+    pub fn new(index: usize, config: Arc<Config>) -> Bot {
+        let room = match config.room_size {
+            0 => "lobby".to_string(),
+            size => format!("bots-{}", index / size as usize),
+        };
+        Bot {
+            index,
+            config,
+            report: BotReport::default(),
+            session: None,
+            room,
+            playing: false,
+            created: Instant::now(),
+            pending_chats: HashMap::new(),
+            chat_sequence: 0,
+            input_sequence: 0,
+            last_snapshot: None,
+        }
+    }
+
+    /// Plays until `end`, then waits a little for the answers still in flight and disconnects.
+    pub async fn run(mut self, end: Instant) -> BotReport {
+        let mut socket = match self.connect().await {
+            Ok(x) => x,
+            Err(e) => {
+                self.report.failed = Some(e);
+                return self.report;
+            },
+        };
+
+        let result = self.play(&mut socket, end).await;
+        if let Err(reason) = result {
+            if self.session.is_none() {
+                self.report.failed = Some(reason);
+            } else {
+                self.report.dropped = Some(reason);
+            }
+        } else if !self.playing && self.report.failed.is_none() {
+            self.report.failed = Some("not in a room by the end of the run".to_string());
+        }
+        let _ = socket.close(None).await;
+        self.report
+    }
+
+    async fn connect(&mut self) -> Result<Socket, String> {
+        let mut request = self.config.url.as_str().into_client_request().map_err(|e| e.to_string())?;
+        request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("rust-websocket"));
+        let (socket, _) = time::timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(request))
+            .await
+            .map_err(|_| "connection timed out".to_string())?
+            .map_err(|e| format!("cannot connect: {}", e))?;
+        Ok(socket)
+    }
+
+    async fn play(&mut self, socket: &mut Socket, end: Instant) -> Result<(), String> {
+        self.send(socket, protocol::Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: format!("bot{}", self.index),
+        }).await?;
+
+        let mut inputs = every(self.config.input_rate);
+        let mut chats = every(self.config.chat_rate);
+        let mut pings = every(self.config.ping_rate);
+        let stop = time::sleep_until(end + GRACE_PERIOD);
+        tokio::pin!(stop);
+
+        loop {
+            let sending = self.playing && Instant::now() < end;
+            tokio::select! {
+                m = socket.next() => match m {
+                    Some(Ok(m)) => self.on_ws_message(socket, m).await?,
+                    Some(Err(e)) => return Err(e.to_string()),
+                    None => return Err("connection lost".to_string()),
+                },
+                _ = tick(&mut inputs), if sending => self.send_input(socket).await?,
+                _ = tick(&mut chats), if sending => self.send_chat(socket).await?,
+                _ = tick(&mut pings), if sending => {
+                    self.report.pings_sent += 1;
+                    self.send(socket, protocol::Message::Ping(self.now())).await?;
+                },
+                _ = &mut stop => return Ok(()),
+            }
+        }
+    }
+
+    async fn on_ws_message(&mut self, socket: &mut Socket, m: WsMessage) -> Result<(), String> {
+        self.report.messages_received += 1;
+        self.report.bytes_received += m.len() as u64;
+        match m {
+            WsMessage::Binary(data) => match deserialize(&data) {
+                Ok(mex) => self.on_message(socket, mex).await,
+                Err(e) => Err(format!("cannot decode a message: {}", e)),
+            },
+            WsMessage::Close(Some(frame)) => Err(format!("closed by the server: {} {}", u16::from(frame.code), frame.reason)),
+            WsMessage::Close(None) => Err("closed by the server".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_message(&mut self, socket: &mut Socket, mex: protocol::Message) -> Result<(), String> {
+        match mex {
+            protocol::Message::Welcome { session_id, .. } => {
+                self.session = Some(session_id);
+                // The server puts everyone in the lobby, the bots move to their own rooms
+                if self.config.room_size > 0 {
+                    let (name, max_players) = (self.room.clone(), self.config.room_size);
+                    self.send(socket, protocol::Message::CreateRoom { name, max_players }).await?;
+                }
+            },
+            protocol::Message::Rejected(reason) => return Err(format!("rejected: {:?}", reason)),
+            protocol::Message::RoomJoined(name) => self.playing = name == self.room,
+            protocol::Message::RoomError(RoomError::AlreadyExists) => {
+                self.send(socket, protocol::Message::JoinRoom(self.room.clone())).await?;
+            },
+            // The lobby can be full while the bots are still leaving it for their rooms
+            protocol::Message::RoomError(RoomError::Full) if self.config.room_size > 0 && !self.playing => {},
+            protocol::Message::RoomError(e) => return Err(format!("room error: {:?}", e)),
+            protocol::Message::Ping(sent) => self.send(socket, protocol::Message::Pong(sent)).await?,
+            protocol::Message::Pong(sent) => {
+                self.report.ping_rtts.push(Duration::from_micros(self.now().saturating_sub(sent)));
+            },
+            protocol::Message::ChatFrom { sender, text, .. } if Some(sender) == self.session => {
+                let sent = text.rsplit(' ').next()
+                    .and_then(|x| x.parse().ok())
+                    .and_then(|sequence| self.pending_chats.remove(&sequence));
+                if let Some(sent) = sent {
+                    self.report.chat_rtts.push(sent.elapsed());
+                }
+            },
+            // The bots only send inputs in their own room, where the count starts
+            protocol::Message::PlayerState { applied, .. } if self.playing => {
+                self.report.inputs_applied = applied as u64;
+            },
+            protocol::Message::Snapshot(delta) => self.last_snapshot = Some(delta.tick),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    async fn send_input(&mut self, socket: &mut Socket) -> Result<(), String> {
+        self.input_sequence += 1;
+        self.report.inputs_sent += 1;
+        let leg = LEGS[(self.input_sequence / LEG_INPUTS) as usize % LEGS.len()];
+        let input = InputCommand {
+            sequence: self.input_sequence,
+            directions: leg.bits(),
+            yaw: Deg(0.0),
+            pitch: Deg(0.0),
+        };
+        self.send(socket, protocol::Message::Input { input, ack: self.last_snapshot }).await
+    }
+
+    async fn send_chat(&mut self, socket: &mut Socket) -> Result<(), String> {
+        self.chat_sequence += 1;
+        self.report.chats_sent += 1;
+        self.pending_chats.insert(self.chat_sequence, Instant::now());
+        let text = format!("bot{} says {}", self.index, self.chat_sequence);
+        self.send(socket, protocol::Message::Chat(text)).await
+    }
+
+    async fn send(&mut self, socket: &mut Socket, mex: protocol::Message) -> Result<(), String> {
+        let data = serialize(mex).expect("cannot serialize");
+        socket.send(WsMessage::Binary(data.into())).await.map_err(|e: WsError| e.to_string())
+    }
+
+    /// Microseconds since the bot was created, the timestamp sent in pings.
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_micros() as u64
+    }
+}
+
+/// Ticks `rate` times per second, never if the rate is 0. Late ticks are delayed instead of
+/// bunched up, a burst would trip the server's rate limits.
+fn every(rate: f64) -> Option<Interval> {
+    if rate <= 0.0 {
+        return None;
+    }
+    let period = Duration::from_secs_f64(1.0 / rate);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(interval)
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(x) => {
+            x.tick().await;
+        },
+        None => future::pending().await,
+    }
+}
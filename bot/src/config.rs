@@ -0,0 +1,126 @@
+use std::{env, fmt};
+use std::str::FromStr;
+use std::time::Duration;
+
+use common::physics::INPUT_RATE;
+
+const USAGE: &str = "\
+Usage: bot [OPTIONS]
+
+Opens many connections to a server, each one playing a scripted player, and reports how the
+server kept up once the run is over.
+
+Options:
+    -u, --url <URL>          Server to connect to [default: ws://127.0.0.1:8081]
+    -n, --clients <N>        Connections to open [default: 10]
+    -d, --duration <SECS>    How long the bots play [default: 30]
+        --ramp-up <SECS>     Time the connections are spread over [default: 5]
+        --input-rate <HZ>    Movement inputs per second of each bot [default: 30]
+        --chat-rate <HZ>     Chat messages per second of each bot [default: 0.2]
+        --ping-rate <HZ>     Latency probes per second of each bot [default: 2]
+        --room-size <N>      Bots in each room, 0 keeps them all in the lobby [default: 16]
+    -h, --help               Print this message
+
+Rates of 0 turn the matching messages off.";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub url: String,
+    pub clients: usize,
+    pub duration: Duration,
+    pub ramp_up: Duration,
+    pub input_rate: f64,
+    pub chat_rate: f64,
+    pub ping_rate: f64,
+    pub room_size: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            url: "ws://127.0.0.1:8081".to_string(),
+            clients: 10,
+            duration: Duration::from_secs(30),
+            ramp_up: Duration::from_secs(5),
+            input_rate: INPUT_RATE as f64,
+            chat_rate: 0.2,
+            ping_rate: 2.0,
+            room_size: 16,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    InvalidValue { name: String, value: String },
+    MissingValue(String),
+    UnknownOption(String),
+    /// Not really an error, the usage has to be printed instead of running
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidValue { name, value } => write!(f, "invalid value for {}: '{}'", name, value),
+            ConfigError::MissingValue(name) => write!(f, "missing value for {}", name),
+            ConfigError::UnknownOption(name) => write!(f, "unknown option {}", name),
+            ConfigError::Help => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the config from the command line, both `--clients 80` and `--clients=80` are
+    /// accepted.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                return Err(ConfigError::Help);
+            }
+
+            let value = match inline.or_else(|| args.next()) {
+                Some(x) => x,
+                None => return Err(ConfigError::MissingValue(flag)),
+            };
+            match flag.as_str() {
+                "-u" | "--url" => config.url = value,
+                "-n" | "--clients" => config.clients = parse_value(&flag, &value)?,
+                "-d" | "--duration" => config.duration = Duration::from_secs_f64(parse_rate(&flag, &value)?),
+                "--ramp-up" => config.ramp_up = Duration::from_secs_f64(parse_rate(&flag, &value)?),
+                "--input-rate" => config.input_rate = parse_rate(&flag, &value)?,
+                "--chat-rate" => config.chat_rate = parse_rate(&flag, &value)?,
+                "--ping-rate" => config.ping_rate = parse_rate(&flag, &value)?,
+                "--room-size" => config.room_size = parse_value(&flag, &value)?,
+                _ => return Err(ConfigError::UnknownOption(flag)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Rates and durations, anything finite and not negative.
+fn parse_rate(name: &str, value: &str) -> Result<f64, ConfigError> {
+    let x: f64 = parse_value(name, value)?;
+    if !x.is_finite() || x < 0.0 {
+        return Err(ConfigError::InvalidValue { name: name.to_string(), value: value.to_string() });
+    }
+    Ok(x)
+}
@@ -0,0 +1,52 @@
+mod bot;
+mod config;
+mod report;
+
+use std::process;
+use std::sync::Arc;
+
+use tokio::time::{self, Instant};
+
+use crate::bot::Bot;
+use crate::config::{Config, ConfigError};
+use crate::report::Report;
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::load() {
+        Ok(x) => x,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return;
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(2);
+        },
+    };
+    println!("Running {} bots against {} for {:?}", config.clients, config.url, config.duration);
+
+    let config = Arc::new(config);
+    let start = Instant::now();
+    // Every bot plays for the whole duration, the last ones to connect end later
+    let bots: Vec<_> = (0..config.clients)
+        .map(|index| {
+            let delay = config.ramp_up.mul_f64(index as f64 / config.clients as f64);
+            let config = config.clone();
+            tokio::spawn(async move {
+                time::sleep_until(start + delay).await;
+                let end = Instant::now() + config.duration;
+                Bot::new(index, config).run(end).await
+            })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for bot in bots {
+        match bot.await {
+            Ok(x) => report.add(x),
+            Err(e) => eprintln!("Bot crashed: {}", e),
+        }
+    }
+    println!("{}", report);
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// What a single bot saw during the run.
+#[derive(Debug, Default)]
+pub struct BotReport {
+    /// Why the bot never got to play, `None` if it completed the handshake
+    pub failed: Option<String>,
+    /// Why the connection ended before the run did, `None` if it lasted until the end
+    pub dropped: Option<String>,
+    pub pings_sent: u64,
+    pub ping_rtts: Vec<Duration>,
+    pub chats_sent: u64,
+    pub chat_rtts: Vec<Duration>,
+    pub inputs_sent: u64,
+    /// Inputs the server said it applied, the ones it dropped aren't counted
+    pub inputs_applied: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// Every bot's report merged, printed at the end of the run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub clients: usize,
+    /// Failed connections and handshakes by reason
+    pub failed: BTreeMap<String, usize>,
+    /// Connections lost during the run by reason
+    pub dropped: BTreeMap<String, usize>,
+    pub pings_sent: u64,
+    pub ping_rtts: Vec<Duration>,
+    pub chats_sent: u64,
+    pub chat_rtts: Vec<Duration>,
+    pub inputs_sent: u64,
+    pub inputs_applied: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+impl Report {
+    pub fn add(&mut self, bot: BotReport) {
+        self.clients += 1;
+        if let Some(reason) = bot.failed {
+            *self.failed.entry(reason).or_insert(0) += 1;
+        }
+        if let Some(reason) = bot.dropped {
+            *self.dropped.entry(reason).or_insert(0) += 1;
+        }
+        self.pings_sent += bot.pings_sent;
+        self.ping_rtts.extend(bot.ping_rtts);
+        self.chats_sent += bot.chats_sent;
+        self.chat_rtts.extend(bot.chat_rtts);
+        self.inputs_sent += bot.inputs_sent;
+        self.inputs_applied += bot.inputs_applied;
+        self.messages_received += bot.messages_received;
+        self.bytes_received += bot.bytes_received;
+    }
+}
+
+/// Nearest-rank percentile of sorted samples, `p` goes from 0 to 100.
+pub fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Share of `sent` that never came back, in percent.
+fn lost(sent: u64, answered: u64) -> f64 {
+    match sent {
+        0 => 0.0,
+        sent => sent.saturating_sub(answered) as f64 * 100.0 / sent as f64,
+    }
+}
+
+fn write_latency(f: &mut fmt::Formatter, name: &str, samples: &[Duration]) -> fmt::Result {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    let ms = |p| percentile(&sorted, p).map_or(0.0, |x| x.as_secs_f64() * 1000.0);
+    writeln!(f, "{} round trip (ms): min {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+             name, ms(0.0), ms(50.0), ms(90.0), ms(99.0), ms(100.0))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed: usize = self.failed.values().sum();
+        let dropped: usize = self.dropped.values().sum();
+        writeln!(f, "Connections: {} opened, {} failed, {} dropped before the end", self.clients - failed, failed, dropped)?;
+        for (reason, count) in self.failed.iter() {
+            writeln!(f, "    {} failed: {}", count, reason)?;
+        }
+        for (reason, count) in self.dropped.iter() {
+            writeln!(f, "    {} dropped: {}", count, reason)?;
+        }
+
+        let pongs = self.ping_rtts.len() as u64;
+        writeln!(f, "Pings: {} sent, {} answered, {:.2}% lost", self.pings_sent, pongs, lost(self.pings_sent, pongs))?;
+        let echoes = self.chat_rtts.len() as u64;
+        writeln!(f, "Chat: {} sent, {} echoed, {:.2}% lost", self.chats_sent, echoes, lost(self.chats_sent, echoes))?;
+        writeln!(f, "Inputs: {} sent, {} applied, {:.2}% lost",
+                 self.inputs_sent, self.inputs_applied, lost(self.inputs_sent, self.inputs_applied))?;
+        write_latency(f, "Ping", &self.ping_rtts)?;
+        write_latency(f, "Chat", &self.chat_rtts)?;
+        write!(f, "Received {} messages, {:.2} MB", self.messages_received, self.bytes_received as f64 / 1_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let ms = Duration::from_millis;
+        let samples: Vec<_> = (1..=100).map(ms).collect();
+        assert_eq!(percentile(&samples, 0.0), Some(ms(1)));
+        assert_eq!(percentile(&samples, 50.0), Some(ms(50)));
+        assert_eq!(percentile(&samples, 99.0), Some(ms(99)));
+        assert_eq!(percentile(&samples, 100.0), Some(ms(100)));
+        assert_eq!(percentile(&[ms(7)], 90.0), Some(ms(7)));
+        assert_eq!(percentile(&[], 50.0), None);
+
+        assert_eq!(lost(0, 0), 0.0);
+        assert_eq!(lost(200, 150), 25.0);
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use cgmath::Vector3;
+use common::heartbeat::RttEstimator;
 use specs::{Dispatcher, DispatcherBuilder, World};
 use specs::prelude::*;
 use specs::shrev::EventChannel;
@@ -11,6 +12,10 @@ use crate::graphics::GraphicContext;
 use crate::graphics::renderer::{ActiveCamera, RenderBody, RenderSystem};
 use crate::graphics::model::RenderModel;
 use crate::input::{KeyboardEvent, MouseMoveEvent, ResizeEvent};
+use crate::network::{NetworkConnection, NetworkSendSystem};
+use crate::network::clock::ServerTime;
+use crate::network::replication::{LastSnapshot, ReplicationSystem};
+use crate::physics::interpolation::{InterpolationBuffer, InterpolationConfig, InterpolationSystem};
 use crate::physics::player_move::PlayerMoveSystem;
 use crate::physics::system::{BodyLocation, UpdateLocation, Velocity};
 use crate::utils::RefClone;
@@ -18,6 +23,10 @@ use crate::utils::RefClone;
 #[derive(Debug, Default)]
 pub struct DeltaTime(pub Duration);
 
+/// Time passed since the app started, the sum of every `DeltaTime`.
+#[derive(Debug, Default)]
+pub struct ElapsedTime(pub Duration);
+
 
 pub struct App {
     pub world: World,
@@ -29,13 +38,20 @@ impl App {
     pub fn create() -> Result<App, JsValue> {
         let mut world = World::new();
         world.insert(DeltaTime(Duration::from_nanos(0)));
+        world.insert(ElapsedTime(Duration::from_nanos(0)));
         world.insert(ActiveCamera(None));
         world.insert(EventChannel::<KeyboardEvent>::new());
         world.insert(EventChannel::<MouseMoveEvent>::new());
         world.insert(EventChannel::<ResizeEvent>::new());
+        world.insert(LastSnapshot(None));
+        world.insert(NetworkConnection::default());
+        world.insert(RttEstimator::default());
+        world.insert(ServerTime::default());
+        world.insert(InterpolationConfig::default());
         world.register::<BodyLocation>();
         world.register::<Velocity>();
         world.register::<RenderBody>();
+        world.register::<InterpolationBuffer>();
 
         let graphics = GraphicContext::from_canvas("canvas")?;
 
@@ -45,13 +61,15 @@ impl App {
 
         let canvas = graphics.canvas.ref_clone();
 
+        let cube_model = RenderModel::new(graphics, CUBE_VERTICES.as_ref());
+
         let player = world.create_entity()
             .with(BodyLocation::at_pos(Vector3 { x: 0.0, y: 0.0, z: 5.0 }))
             .build();
 
         let cube = world.create_entity()
             .with(BodyLocation::zero())
-            .with(RenderBody::from_model(RenderModel::new(graphics, CUBE_VERTICES.as_ref())))
+            .with(RenderBody::from_model(cube_model.clone()))
             .build();
 
         {
@@ -59,10 +77,15 @@ impl App {
             active_camera.0 = Some(player);
         }
 
+        let replication_system = ReplicationSystem::new(&mut world, cube_model);
+
         let dispatcher = DispatcherBuilder::new()
             .with(UpdateLocation, "update_location", &[])
             .with(PlayerMoveSystem::new(&mut world), "player_move", &[])
+            .with_thread_local(replication_system)
+            .with_thread_local(InterpolationSystem)
             .with_thread_local(render_system)
+            .with_thread_local(NetworkSendSystem)
             .build();
 
         //dispatcher.setup(&mut world);
@@ -74,11 +97,14 @@ impl App {
         })
     }
 
-    pub fn update(&mut self, delta: Duration) {
+    /// Runs a frame, `now` is the `performance.now()` time of the frame in milliseconds.
+    pub fn update(&mut self, delta: Duration, now: f64) {
         {// Update delta
             let deltatime = self.world.get_mut::<DeltaTime>().unwrap();
             deltatime.0 = delta
         }
+        self.world.get_mut::<ElapsedTime>().unwrap().0 += delta;
+        self.world.get_mut::<ServerTime>().unwrap().update(now);
 
         self.dispatcher.dispatch(&self.world);
         self.world.maintain();
@@ -1,39 +1,102 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::heartbeat::RttEstimator;
 use common::protocol::*;
 use js_sys::{ArrayBuffer, Uint8Array};
+use specs::WorldExt;
 use wasm_bindgen::JsCast;
 use wasm_bindgen::JsValue;
 use wasm_bindgen::prelude::*;
-use web_sys::{BinaryType, ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, MessageEvent, UrlSearchParams, WebSocket};
 
+use crate::app::App;
 use crate::console_log;
+use crate::network::clock::ServerTime;
+use crate::network::{now_micros, ConnectionState, NetworkConnection};
+
+const CLIENT_NAME: &str = "wasm-client";
+const DEFAULT_PORT: u16 = 8081;
+/// Query string option that overrides the server url, as in `?server=wss://example.com:8081`
+const SERVER_QUERY_OPTION: &str = "server";
+
+/// Picks the server's url, the one asked by the page comes first, then the one in the query
+/// string. Without either the page's host is used, over `wss://` if the page is served securely.
+pub fn server_url(requested: Option<String>) -> Result<String, JsValue> {
+    let location = web_sys::window().expect("no global window").location();
+    let secure = location.protocol()? == "https:";
 
-pub fn on_data(e: MessageEvent) {
-    //let packet = deserialize();
-    let packet = e.data();
+    let from_query = UrlSearchParams::new_with_str(&location.search()?)?.get(SERVER_QUERY_OPTION);
+    let url = match requested.or(from_query).filter(|x| !x.is_empty()) {
+        Some(x) => x,
+        None => {
+            let host = location.hostname()?;
+            let host = if host.is_empty() { "localhost".to_string() } else { host };
+            format!("{}:{}", host, DEFAULT_PORT)
+        },
+    };
+
+    if url.starts_with("ws://") || url.starts_with("wss://") {
+        Ok(url)
+    } else {
+        Ok(format!("{}://{}", if secure { "wss" } else { "ws" }, url))
+    }
+}
 
-    let casted: Result<ArrayBuffer, JsValue> = packet.dyn_into();
+fn read_data(e: &MessageEvent) -> Option<Vec<u8>> {
+    let casted: Result<ArrayBuffer, JsValue> = e.data().dyn_into();
 
     match casted {
         Ok(buffer) => {
             let typedbuf: Uint8Array = js_sys::Uint8Array::new(&buffer);
             let mut data: Vec<u8> = vec![0; typedbuf.length() as usize];
             typedbuf.copy_to(data.as_mut_slice());
-            //console::log_2(&JsValue::from_str("message event, received buffer: "), &buffer)
-            let mex = deserialize(&data).expect("cannot deserialize");
-            console_log!("message event, received data: {:?}", mex)
+            Some(data)
         }
-        Err(val) => console_log!("message event, received data: {:?}", val),
+        Err(val) => {
+            console_log!("message event, received data: {:?}", val);
+            None
+        },
+    }
+}
+
+fn on_data(app: &RefCell<App>, e: MessageEvent) {
+    let data = match read_data(&e) {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mex = match deserialize(&data) {
+        Ok(x) => x,
+        Err(e) => return console_log!("message event, invalid data: {}", e),
+    };
+
+    let app = app.borrow_mut();
+    match mex {
+        Message::Pong(sent) => app.world.write_resource::<RttEstimator>().add_ping(sent, now_micros()),
+        Message::TimeResponse { client_time, server_time } => {
+            app.world.write_resource::<ServerTime>().add_sample(client_time, server_time, now_micros());
+        },
+        // The new session might be on another server, the old clock means nothing
+        Message::Welcome { tick_rate, .. } => *app.world.write_resource::<ServerTime>() = ServerTime::new(tick_rate),
+        _ => {},
     }
+    app.world.write_resource::<NetworkConnection>().receive(mex);
 }
 
-pub fn start_websocket() -> Result<(), JsValue> {
-    // Connect to an echo server
-    let ws = WebSocket::new_with_str("ws://localhost:8081", "rust-websocket")?;
+/// Opens the websocket and plugs it into the app's `NetworkConnection`.
+pub fn start_websocket(app: Rc<RefCell<App>>, url: String) -> Result<(), JsValue> {
+    console_log!("connecting to {}", url);
+    let ws = WebSocket::new_with_str(&url, "rust-websocket")?;
 
     ws.set_binary_type(BinaryType::Arraybuffer);
 
     // create callback
-    let onmessage_callback = Closure::wrap(Box::new(on_data) as Box<dyn FnMut(MessageEvent)>);
+    let message_app = app.clone();
+    let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
+        on_data(&message_app, e);
+    }) as Box<dyn FnMut(MessageEvent)>);
     // set message event handler on WebSocket
     ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
     // forget the callback to keep it alive
@@ -45,16 +108,47 @@ pub fn start_websocket() -> Result<(), JsValue> {
     ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
     onerror_callback.forget();
 
-    let cloned_ws = ws.clone();
+    let close_app = app.clone();
+    let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
+        console_log!("socket closed: {} {}", e.code(), e.reason());
+        let retry = close_app.borrow_mut().world.write_resource::<NetworkConnection>().on_close();
+        if let Some(delay) = retry {
+            console_log!("reconnecting in {}ms", delay.as_millis());
+            reconnect_after(close_app.clone(), url.clone(), delay);
+        }
+    }) as Box<dyn FnMut(CloseEvent)>);
+    ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+    onclose_callback.forget();
+
+    let open_app = app.clone();
     let onopen_callback = Closure::wrap(Box::new(move |_| {
         console_log!("socket opened");
-        match cloned_ws.send_with_u8_array(serialize(Message::Ping).expect("cannot serialize").as_mut_slice()) {
-            Ok(_) => console_log!("message successfully sent"),
-            Err(err) => console_log!("error sending message: {:?}", err),
-        }
+        let app = open_app.borrow();
+        let mut connection = app.world.write_resource::<NetworkConnection>();
+        connection.on_open();
+        // Every connection is a new session, even when it's a reconnection
+        connection.send(Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: CLIENT_NAME.to_string(),
+        });
     }) as Box<dyn FnMut(JsValue)>);
     ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
     onopen_callback.forget();
 
+    app.borrow_mut().world.write_resource::<NetworkConnection>().on_connecting(ws);
+
     Ok(())
-}
\ No newline at end of file
+}
+
+fn reconnect_after(app: Rc<RefCell<App>>, url: String, delay: Duration) {
+    let callback = Closure::once_into_js(move || {
+        if let Err(e) = start_websocket(app.clone(), url) {
+            console_log!("cannot reconnect: {:?}", e);
+            app.borrow_mut().world.write_resource::<NetworkConnection>().state = ConnectionState::Closed;
+        }
+    });
+
+    let window = web_sys::window().expect("no global window");
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+        callback.unchecked_ref(), delay.as_millis() as i32);
+}
@@ -5,6 +5,7 @@ mod graphics;
 mod physics;
 mod web;
 mod input;
+mod network;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
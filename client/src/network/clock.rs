@@ -0,0 +1,37 @@
+use common::clock::ClockSync;
+
+/// The server's clock as estimated by the client, it's synchronized with `TimeRequest`s.
+#[derive(Debug, Clone, Default)]
+pub struct ServerTime {
+    sync: ClockSync,
+    tick_rate: u32,
+    /// Server tick matching the time of the current frame, `None` until the clock is synchronized
+    pub tick: Option<f64>,
+}
+
+impl ServerTime {
+    pub fn new(tick_rate: u32) -> ServerTime {
+        ServerTime {
+            tick_rate,
+            ..ServerTime::default()
+        }
+    }
+
+    /// Adds the answer to a `TimeRequest`, all times are in microseconds.
+    pub fn add_sample(&mut self, sent: u64, server_time: u64, received: u64) {
+        self.sync.add_sample(sent, server_time, received);
+    }
+
+    /// Server tick matching the local `performance.now()` time `local_ms`, ticks are fractional so
+    /// they can be used to interpolate.
+    pub fn tick_at(&self, local_ms: f64) -> Option<f64> {
+        let offset = self.sync.offset()?;
+        let server_micros = local_ms * 1000.0 + offset as f64;
+        Some(server_micros * self.tick_rate as f64 / 1_000_000.0)
+    }
+
+    /// Updates `tick` for a new frame.
+    pub fn update(&mut self, local_ms: f64) {
+        self.tick = self.tick_at(local_ms);
+    }
+}
@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use common::clock::CLOCK_SYNC_SAMPLES;
+use common::heartbeat::HEARTBEAT_INTERVAL;
+use common::protocol::{serialize, Message, SessionId};
+use specs::{System, Write};
+use specs::shrev::EventChannel;
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+use crate::console_log;
+
+pub mod clock;
+pub mod replication;
+
+/// Wait before the first reconnection attempt, it doubles after each failed one.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between two reconnection attempts.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Wait between the time requests sent right after joining, the clock is useless until they're in.
+const CLOCK_SYNC_BURST_INTERVAL: Duration = Duration::from_millis(100);
+/// Wait between the time requests that keep the clock from drifting away.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    /// The socket is being opened
+    Connecting,
+    /// The socket is open, the handshake might still be running
+    Open,
+    /// The connection was lost, waiting before trying again
+    Backoff,
+    /// No connection and no retries, the server refused us
+    #[default]
+    Closed,
+}
+
+/// The link to the server as seen by the systems, they never touch the websocket directly.
+#[derive(Default)]
+pub struct NetworkConnection {
+    /// Every message received from the server, in order
+    pub inbound: EventChannel<Message>,
+    /// Messages produced by the systems during a frame, waiting to be sent to the server
+    pub outbound: Vec<Message>,
+    /// Assigned by the server's `Welcome`, `None` until the handshake is completed
+    pub session_id: Option<SessionId>,
+    pub state: ConnectionState,
+    /// Connection attempts failed since the last successful handshake
+    attempts: u32,
+    /// When the server said it would be back, used instead of the backoff for the next attempt
+    reconnect_after: Option<Duration>,
+    /// When the next heartbeat is due, in the same microseconds as `now_micros`
+    next_ping: u64,
+    next_time_request: u64,
+    /// Time requests sent during this session
+    time_requests: usize,
+    socket: Option<WebSocket>,
+}
+
+// The browser runs everything on a single thread, the socket is never shared between threads
+unsafe impl Send for NetworkConnection {}
+unsafe impl Sync for NetworkConnection {}
+
+impl NetworkConnection {
+    pub fn on_connecting(&mut self, socket: WebSocket) {
+        self.state = ConnectionState::Connecting;
+        self.socket = Some(socket);
+    }
+
+    pub fn on_open(&mut self) {
+        self.state = ConnectionState::Open;
+    }
+
+    /// Forgets the lost socket, returns how long to wait before reconnecting if we should at all.
+    pub fn on_close(&mut self) -> Option<Duration> {
+        self.socket = None;
+        self.session_id = None;
+        if self.state == ConnectionState::Closed {
+            return None;
+        }
+
+        self.state = ConnectionState::Backoff;
+        if let Some(delay) = self.reconnect_after.take() {
+            // Everyone got the same hint, spread the reconnections a bit
+            return Some(delay.max(RECONNECT_BASE_DELAY).mul_f64(1.0 + js_sys::Math::random() * 0.5));
+        }
+        self.attempts += 1;
+        Some(self.backoff_delay())
+    }
+
+    /// Exponential backoff with jitter, so clients dropped together don't all come back together.
+    fn backoff_delay(&self) -> Duration {
+        let exponent = self.attempts.saturating_sub(1).min(16);
+        let delay = (RECONNECT_BASE_DELAY * 2u32.pow(exponent)).min(RECONNECT_MAX_DELAY);
+        delay.mul_f64(0.5 + js_sys::Math::random() * 0.5)
+    }
+
+    /// Sends a message right away, skipping the outbound queue.
+    pub fn send(&self, mex: Message) {
+        let socket = match &self.socket {
+            Some(x) => x,
+            None => return,
+        };
+        let mut data = serialize(mex).expect("cannot serialize");
+        if let Err(e) = socket.send_with_u8_array(data.as_mut_slice()) {
+            console_log!("error sending message: {:?}", e);
+        }
+    }
+
+    /// Receives a message from the server, the handshake reply is handled here as no system
+    /// needs to know about it.
+    pub fn receive(&mut self, mex: Message) {
+        match &mex {
+            Message::Welcome { session_id, server_version, .. } => {
+                console_log!("Joined server {} as session {}", server_version, session_id);
+                self.session_id = Some(*session_id);
+                self.attempts = 0;
+                self.next_ping = 0;
+                self.next_time_request = 0;
+                self.time_requests = 0;
+            },
+            // Answered right away, waiting for the next frame would make the round trip longer
+            Message::Ping(sent) => self.send(Message::Pong(*sent)),
+            Message::ServerShutdown { reason, reconnect_after } => {
+                console_log!("The server is shutting down: {}", reason);
+                self.reconnect_after = reconnect_after.map(|x| Duration::from_secs(x as u64));
+            },
+            Message::Rejected(reason) => {
+                // Trying again would only get the same answer
+                console_log!("Rejected by the server: {:?}", reason);
+                self.state = ConnectionState::Closed;
+            },
+            _ => {},
+        }
+        self.inbound.single_write(mex);
+    }
+
+    /// Pings the server and asks for its time when it's due.
+    pub fn heartbeat(&mut self, now: u64) {
+        if self.session_id.is_none() {
+            return;
+        }
+
+        if now >= self.next_ping {
+            self.send(Message::Ping(now));
+            self.next_ping = now + HEARTBEAT_INTERVAL.as_micros() as u64;
+        }
+
+        if now >= self.next_time_request {
+            self.send(Message::TimeRequest { client_time: now });
+            self.time_requests += 1;
+            let interval = if self.time_requests < CLOCK_SYNC_SAMPLES {
+                CLOCK_SYNC_BURST_INTERVAL
+            } else {
+                CLOCK_SYNC_INTERVAL
+            };
+            self.next_time_request = now + interval.as_micros() as u64;
+        }
+    }
+}
+
+/// Microseconds since the page was loaded, from the browser's monotonic clock.
+pub fn now_micros() -> u64 {
+    let performance = web_sys::window()
+        .and_then(|window| window.performance())
+        .expect("performance should be available");
+    (performance.now() * 1000.0) as u64
+}
+
+/// Sends everything the other systems queued during the frame, it should run last.
+pub struct NetworkSendSystem;
+
+impl<'a> System<'a> for NetworkSendSystem {
+    type SystemData = Write<'a, NetworkConnection>;
+
+    fn run(&mut self, mut connection: Self::SystemData) {
+        let outbound: Vec<Message> = connection.outbound.drain(..).collect();
+        // Anything but the handshake before the Welcome would get us kicked out
+        if connection.session_id.is_none() {
+            return;
+        }
+        for mex in outbound {
+            connection.send(mex);
+        }
+        connection.heartbeat(now_micros());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use common::protocol::{EntityState, Message, ModelKind, NetId};
+use common::snapshot::{SnapshotDelta, WorldSnapshot};
+use specs::prelude::*;
+use specs::{Entities, Read, ReaderId, System, Write, WriteStorage};
+
+use crate::app::ElapsedTime;
+use crate::graphics::model::RenderModel;
+use crate::graphics::renderer::RenderBody;
+use crate::physics::interpolation::InterpolationBuffer;
+use crate::physics::system::BodyLocation;
+use crate::network::NetworkConnection;
+use crate::console_log;
+
+/// The server only builds deltas against recent snapshots, older ones are useless.
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// Tick of the newest snapshot received, sent back to the server as the next delta baseline.
+#[derive(Debug, Default)]
+pub struct LastSnapshot(pub Option<u32>);
+
+/// Mirrors the entities the server tells us about into the local world, their locations are
+/// buffered and left to the `InterpolationSystem`.
+pub struct ReplicationSystem {
+    reader: ReaderId<Message>,
+    // Server ids never change during an entity's lifetime so they can be mapped once at spawn
+    entities: HashMap<NetId, Entity>,
+    /// Encoded snapshots that the server might use as baselines, oldest first
+    history: VecDeque<(u32, Vec<u8>)>,
+    cube: RenderModel,
+}
+
+impl ReplicationSystem {
+    pub fn new(world: &mut World, cube: RenderModel) -> ReplicationSystem {
+        ReplicationSystem {
+            reader: world.write_resource::<NetworkConnection>().inbound.register_reader(),
+            entities: HashMap::new(),
+            history: VecDeque::new(),
+            cube,
+        }
+    }
+
+    fn model(&self, kind: ModelKind) -> RenderModel {
+        match kind {
+            ModelKind::Cube => self.cube.clone(),
+        }
+    }
+
+    fn spawn(&mut self, state: &EntityState, time: Duration, entities: &Entities, data: &mut SpawnData) {
+        // A spawn for a known id only refreshes it, the local entity stays the same
+        let entity = *self.entities.entry(state.id).or_insert_with(|| entities.create());
+        let _ = data.0.insert(entity, state.location.clone());
+        let _ = data.1.insert(entity, InterpolationBuffer::new(time, state.location.clone()));
+        let _ = data.2.insert(entity, RenderBody::from_model(self.model(state.kind)));
+    }
+
+    /// Rebuilds the snapshot from the delta, remembering it as a future baseline.
+    fn read_snapshot(&mut self, delta: &SnapshotDelta) -> Option<WorldSnapshot> {
+        if self.history.back().is_some_and(|(tick, _)| *tick >= delta.tick) {
+            // Out of date, a newer one was already applied
+            return None;
+        }
+
+        let baseline = match delta.baseline {
+            Some(base_tick) => match self.history.iter().find(|(tick, _)| *tick == base_tick) {
+                Some((_, bytes)) => Some(bytes.as_slice()),
+                None => {
+                    console_log!("Missing snapshot baseline {}", base_tick);
+                    return None;
+                },
+            },
+            None => None,
+        };
+
+        let decoded = delta.apply(baseline)
+            .and_then(|bytes| WorldSnapshot::from_bytes(&bytes).map(|snapshot| (bytes, snapshot)));
+        let (bytes, snapshot) = match decoded {
+            Ok(x) => x,
+            Err(e) => {
+                console_log!("Invalid snapshot {}: {}", delta.tick, e);
+                return None;
+            },
+        };
+
+        if self.history.len() >= SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((delta.tick, bytes));
+        Some(snapshot)
+    }
+}
+
+type SpawnData<'a> = (
+    WriteStorage<'a, BodyLocation>,
+    WriteStorage<'a, InterpolationBuffer>,
+    WriteStorage<'a, RenderBody>,
+);
+
+impl<'a> System<'a> for ReplicationSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, NetworkConnection>,
+        Read<'a, ElapsedTime>,
+        Write<'a, LastSnapshot>,
+        SpawnData<'a>,
+    );
+
+    fn run(&mut self, (entities, connection, time, mut last_snapshot, mut data): Self::SystemData) {
+        // Samples are timed by arrival, the interpolation delay hides the network's jitter
+        let now = time.0;
+        for mex in connection.inbound.read(&mut self.reader) {
+            match mex {
+                Message::EntitySpawned(state) => self.spawn(state, now, &entities, &mut data),
+                Message::Snapshot(delta) => {
+                    let snapshot = match self.read_snapshot(delta) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    last_snapshot.0 = Some(snapshot.tick);
+
+                    for state in snapshot.entities.iter() {
+                        match self.entities.get(&state.id) {
+                            Some(&entity) => {
+                                if let Some(buffer) = data.1.get_mut(entity) {
+                                    buffer.push(now, state.location.clone());
+                                }
+                            },
+                            // Spawns are sent on their own but the snapshot has all that's needed
+                            None => self.spawn(state, now, &entities, &mut data),
+                        }
+                    }
+                },
+                Message::EntityDespawned(id) => {
+                    if let Some(entity) = self.entities.remove(id) {
+                        let _ = entities.delete(entity);
+                    }
+                },
+                // A new session, possibly on a restarted server, or another room's simulation,
+                // the old state means nothing
+                Message::Welcome { .. } | Message::RoomJoined(_) | Message::RoomLeft => {
+                    for (_, entity) in self.entities.drain() {
+                        let _ = entities.delete(entity);
+                    }
+                    self.history.clear();
+                    last_snapshot.0 = None;
+                },
+                _ => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use cgmath::prelude::*;
+use cgmath::Deg;
+use specs::{Component, Join, Read, System, VecStorage, WriteStorage};
+
+use crate::app::ElapsedTime;
+use crate::physics::system::BodyLocation;
+
+/// Samples older than this are never needed, a buffer this long means no one is reading it.
+const MAX_SAMPLES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct InterpolationConfig {
+    /// How far in the past remote entities are shown, it should cover a couple of snapshots
+    pub delay: Duration,
+    /// How long an entity keeps moving on its own when snapshots stop arriving
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        InterpolationConfig {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Locations received from the server for a remote entity, each with the time it arrived.
+#[derive(Debug, Default)]
+pub struct InterpolationBuffer {
+    samples: VecDeque<(Duration, BodyLocation)>,
+}
+
+impl InterpolationBuffer {
+    pub fn new(time: Duration, location: BodyLocation) -> InterpolationBuffer {
+        let mut buffer = InterpolationBuffer::default();
+        buffer.push(time, location);
+        buffer
+    }
+
+    pub fn push(&mut self, time: Duration, location: BodyLocation) {
+        if self.samples.back().is_some_and(|(last, _)| *last > time) {
+            return;
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, location));
+    }
+
+    /// Location at `time`, samples before the ones needed to compute it are dropped.
+    pub fn sample(&mut self, time: Duration, max_extrapolation: Duration) -> Option<BodyLocation> {
+        // Keep the newest sample older than `time`, it's the start of the current segment
+        while self.samples.len() > 2 && self.samples[1].0 <= time {
+            self.samples.pop_front();
+        }
+
+        let (first_time, first) = self.samples.front()?;
+        if time <= *first_time || self.samples.len() == 1 {
+            return Some(first.clone());
+        }
+
+        let (start_time, start) = &self.samples[0];
+        let (end_time, end) = &self.samples[1];
+        let span = (*end_time - *start_time).as_secs_f32();
+        if span <= 0.0 {
+            return Some(end.clone());
+        }
+
+        if time <= *end_time {
+            let t = (time - *start_time).as_secs_f32() / span;
+            return Some(lerp_location(start, end, t));
+        }
+
+        // Late packet, keep going the same way but not for too long
+        let ahead = (time - *end_time).min(max_extrapolation).as_secs_f32();
+        let mut location = end.clone();
+        location.pos = end.pos + (end.pos - start.pos) * (ahead / span);
+        Some(location)
+    }
+}
+
+impl Component for InterpolationBuffer {
+    type Storage = VecStorage<Self>;
+}
+
+fn lerp_location(from: &BodyLocation, to: &BodyLocation, t: f32) -> BodyLocation {
+    BodyLocation {
+        pos: from.pos.lerp(to.pos, t),
+        yaw: slerp_angle(from.yaw, to.yaw, t),
+        pitch: slerp_angle(from.pitch, to.pitch, t),
+    }
+}
+
+/// Rotates along the shortest arc, so going from 350° to 10° doesn't spin all the way around.
+fn slerp_angle(from: Deg<f32>, to: Deg<f32>, t: f32) -> Deg<f32> {
+    let diff = (to - from).normalize_signed();
+    (from + diff * t).normalize()
+}
+
+
+/// Moves every remote entity where it was `InterpolationConfig::delay` ago.
+pub struct InterpolationSystem;
+
+impl<'a> System<'a> for InterpolationSystem {
+    type SystemData = (
+        WriteStorage<'a, InterpolationBuffer>,
+        WriteStorage<'a, BodyLocation>,
+        Read<'a, ElapsedTime>,
+        Read<'a, InterpolationConfig>,
+    );
+
+    fn run(&mut self, (mut buffers, mut locations, time, config): Self::SystemData) {
+        let render_time = match time.0.checked_sub(config.delay) {
+            Some(x) => x,
+            None => return,
+        };
+
+        for (buffer, location) in (&mut buffers, &mut locations).join() {
+            if let Some(sampled) = buffer.sample(render_time, config.max_extrapolation) {
+                *location = sampled;
+            }
+        }
+    }
+}
@@ -1,2 +1,3 @@
+pub mod interpolation;
 pub mod system;
-pub mod player_move;
\ No newline at end of file
+pub mod player_move;
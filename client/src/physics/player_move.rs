@@ -1,29 +1,44 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
 use specs::prelude::*;
-use specs::{System, ReaderId, Read};
+use specs::{System, ReaderId, Read, Write};
 use specs::shrev::EventChannel;
+use common::physics::{apply_input, InputCommand, MoveDirection, INPUT_RATE};
+use common::protocol::Message;
+use crate::app::DeltaTime;
 use crate::input::{KeyboardEvent, KeyState, MouseMoveEvent};
+use crate::network::NetworkConnection;
+use crate::network::replication::LastSnapshot;
 use crate::physics::system::BodyLocation;
 use crate::graphics::renderer::ActiveCamera;
-use bitflags::bitflags;
 use cgmath::prelude::*;
-use cgmath::{Vector3, Deg};
+use cgmath::Deg;
 use crate::console_log;
 
-
-bitflags! {
-    #[derive(Default)]
-    struct MoveDirection: u8 {
-        const FORWARD = 0b0001;
-        const BACK  = 0b0010;
-        const LEFT  = 0b0100;
-        const RIGHT = 0b1000;
-    }
-}
-
+/// Inputs waiting for the server to apply them, past this the server is surely not listening.
+const MAX_PENDING_INPUTS: usize = 128;
+/// Distance between the predicted and the replayed position that is still considered a match.
+const RECONCILE_EPSILON: f32 = 0.01;
+/// Inputs sampled in a single frame at most, a long frame (like a tab coming back from the
+/// background) drops the rest of its time instead of flooding the server's rate limit.
+const MAX_CATCHUP_STEPS: u32 = 4;
+
+
+/// Moves the local player predicting what the server will do with the inputs it's sent.
+///
+/// Inputs are sampled at the fixed `INPUT_RATE` and applied right away, they're kept until the
+/// server acknowledges them with a `PlayerState`. The authoritative location is then moved forward
+/// replaying the inputs the server hasn't seen yet and the player is only snapped to it if the
+/// prediction ended up somewhere else.
 pub struct PlayerMoveSystem {
     keyboard_reader: ReaderId<KeyboardEvent>,
     mouse_reader: ReaderId<MouseMoveEvent>,
+    message_reader: ReaderId<Message>,
     dir: MoveDirection,
+    last_sequence: u32,
+    pending: VecDeque<InputCommand>,
+    accumulator: Duration,
 }
 
 
@@ -32,7 +47,11 @@ impl PlayerMoveSystem {
         PlayerMoveSystem {
             keyboard_reader: world.write_resource::<EventChannel<KeyboardEvent>>().register_reader(),
             mouse_reader: world.write_resource::<EventChannel<MouseMoveEvent>>().register_reader(),
+            message_reader: world.write_resource::<NetworkConnection>().inbound.register_reader(),
             dir: MoveDirection::empty(),
+            last_sequence: 0,
+            pending: VecDeque::new(),
+            accumulator: Duration::from_secs(0),
         }
     }
 
@@ -46,28 +65,30 @@ impl PlayerMoveSystem {
         }
     }
 
-    fn apply_vel(&self, player: &mut BodyLocation) {
-        const VEL: f32 = 0.1;
-
-        let mut dir: Vector3<f32> = Vector3::zero() as Vector3<f32>;
-
-        if self.dir.contains(MoveDirection::FORWARD) {
-            dir.z -= 1.0;
-        }
-        if self.dir.contains(MoveDirection::BACK) {
-            dir.z += 1.0;
+    fn next_input(&mut self, player: &BodyLocation) -> InputCommand {
+        self.last_sequence += 1;
+        InputCommand {
+            sequence: self.last_sequence,
+            directions: self.dir.bits(),
+            yaw: player.yaw,
+            pitch: player.pitch,
         }
-        if self.dir.contains(MoveDirection::LEFT) {
-            dir.x -= 1.0;
-        }
-        if self.dir.contains(MoveDirection::RIGHT) {
-            dir.x += 1.0;
+    }
+
+    fn reconcile(&mut self, player: &mut BodyLocation, last_input: u32, server_loc: &BodyLocation) {
+        while self.pending.front().is_some_and(|x| x.sequence <= last_input) {
+            self.pending.pop_front();
         }
 
+        let mut replayed = server_loc.clone();
+        for input in self.pending.iter() {
+            apply_input(&mut replayed, input);
+        }
 
-        if !dir.is_zero() {
-            console_log!("Vel: {:?}", dir);
-            player.forward(dir.normalize() * VEL);
+        // The view direction always belongs to the client, only the position is corrected
+        if (replayed.pos - player.pos).magnitude() > RECONCILE_EPSILON {
+            console_log!("Prediction error, moving from {:?} to {:?}", player.pos, replayed.pos);
+            player.pos = replayed.pos;
         }
     }
 }
@@ -78,9 +99,13 @@ impl<'a> System<'a> for PlayerMoveSystem {
         Read<'a, ActiveCamera>,
         Read<'a, EventChannel<KeyboardEvent>>,
         Read<'a, EventChannel<MouseMoveEvent>>,
+        Read<'a, DeltaTime>,
+        Read<'a, LastSnapshot>,
+        Write<'a, NetworkConnection>,
     );
 
-    fn run(&mut self, (mut location, camera, keyboard_events, mouse_events): Self::SystemData) {
+    fn run(&mut self, (mut location, camera, keyboard_events, mouse_events, delta, last_snapshot,
+                       mut connection): Self::SystemData) {
         let camera_loc = camera.0.and_then(|e| location.get_mut(e));
 
         // Update direction
@@ -92,6 +117,21 @@ impl<'a> System<'a> for PlayerMoveSystem {
                 self.dir.remove(dir);
             }
         }
+
+        let mut server_state = None;
+        for mex in connection.inbound.read(&mut self.message_reader) {
+            match mex {
+                // A new session or room knows nothing about the inputs sent to the old one
+                Message::Welcome { .. } | Message::RoomJoined(_) => {
+                    self.pending.clear();
+                    server_state = None;
+                },
+                // Only the newest authoritative state is worth replaying from
+                Message::PlayerState { last_input, location, .. } => server_state = Some((*last_input, location)),
+                _ => {},
+            }
+        }
+
         //self.graphics.camera.rotate(Deg(dx as f32 * PREC), Deg(dy as f32 * PREC));
         // Update rotation
 
@@ -102,8 +142,24 @@ impl<'a> System<'a> for PlayerMoveSystem {
                 loc.rotate(Deg(event.dx as f32 * sensibility), Deg(event.dy as f32 * sensibility));
             }
 
+            if let Some((last_input, server_loc)) = server_state {
+                self.reconcile(loc, last_input, server_loc);
+            }
+
+            let step = Duration::from_secs(1) / INPUT_RATE;
+            self.accumulator = (self.accumulator + delta.0).min(step * MAX_CATCHUP_STEPS);
+            while self.accumulator >= step {
+                self.accumulator -= step;
 
-            self.apply_vel(loc);
+                let input = self.next_input(loc);
+                apply_input(loc, &input);
+
+                if self.pending.len() >= MAX_PENDING_INPUTS {
+                    self.pending.pop_front();
+                }
+                self.pending.push_back(input.clone());
+                connection.outbound.push(Message::Input { input, ack: last_snapshot.0 });
+            }
         } else {
             console_log!("No active player found");
             mouse_events.read(&mut self.mouse_reader);
@@ -6,12 +6,16 @@ use wasm_bindgen::JsValue;
 use wasm_bindgen::prelude::*;
 use web_sys::KeyboardEvent as WebKeyboardEvent;
 
+use common::heartbeat::RttEstimator;
+
 use crate::app::App;
+use crate::connection;
 use crate::console_log;
 use crate::utils;
 use specs::WorldExt;
 use specs::shrev::EventChannel;
 use crate::input::{KeyboardEvent, KeyState, MouseMoveEvent, ResizeEvent};
+use crate::network::{ConnectionState, NetworkConnection};
 
 #[wasm_bindgen]
 pub struct WebApp {
@@ -21,15 +25,16 @@ pub struct WebApp {
 
 #[wasm_bindgen]
 impl WebApp {
-    pub fn create() -> Result<WebApp, JsValue> {
+    /// Starts the app connecting to `server_url`, see `connection::server_url` for the fallbacks.
+    pub fn create(server_url: Option<String>) -> Result<WebApp, JsValue> {
         utils::set_panic_hook();
         console_log!("Starting up");
 
-        //connection::start_websocket().expect("Cannot start websocket");
-        let app = App::create()?;
+        let app = Rc::new(RefCell::new(App::create()?));
+        connection::start_websocket(app.clone(), connection::server_url(server_url)?)?;
 
         let mut webapp = WebApp{
-            app: Rc::new(RefCell::new(app)),
+            app,
             last_time: 0,
         };
 
@@ -40,6 +45,17 @@ impl WebApp {
         Ok(webapp)
     }
 
+    pub fn connection_state(&self) -> ConnectionState {
+        self.app.borrow().world.read_resource::<NetworkConnection>().state
+    }
+
+    /// Smoothed round trip time to the server in milliseconds, if it was measured yet.
+    pub fn rtt(&self) -> Option<f64> {
+        let app = self.app.borrow();
+        let rtt = app.world.read_resource::<RttEstimator>().rtt();
+        rtt.map(|x| x.as_secs_f64() * 1000.0)
+    }
+
     pub fn on_click(&self) {
         console_log!("click");
     }
@@ -72,7 +88,7 @@ impl WebApp {
         }*/
         let deltatime = now - self.last_time;
 
-        self.app.borrow_mut().update(Duration::from_millis(deltatime as u64));
+        self.app.borrow_mut().update(Duration::from_millis(deltatime as u64), now as f64);
 
         self.last_time = now;
     }
@@ -1,9 +1,10 @@
-import { WebApp }  from "wasm-test-02";
+import { WebApp, ConnectionState }  from "wasm-test-02";
 
 
 let app = WebApp.create();
 
 let canvas = document.getElementById("canvas");
+let status = document.getElementById("status");
 
 canvas.onclick = function() {
     if (document.pointerLockElement !== canvas) {
@@ -45,8 +46,28 @@ function onResize() {
     app.on_resize();
 }
 
+function updateStatus() {
+    switch (app.connection_state()) {
+        case ConnectionState.Open:
+            let rtt = app.rtt();
+            status.hidden = rtt === undefined;
+            status.textContent = "Ping: " + Math.round(rtt) + " ms";
+            return;
+        case ConnectionState.Closed:
+            status.textContent = "Disconnected";
+            break;
+        case ConnectionState.Connecting:
+            status.textContent = "Connecting\u2026";
+            break;
+        default:
+            status.textContent = "Reconnecting\u2026";
+    }
+    status.hidden = false;
+}
+
 function update() {
     app.update(performance.now());
+    updateStatus();
     requestAnimationFrame(update);
 }
 
@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+
+/// Clock samples kept, the estimate is the median of these.
+pub const CLOCK_SYNC_SAMPLES: usize = 8;
+
+/// Estimates the offset between the local clock and the server's from `TimeRequest` round trips,
+/// like NTP does. A single sample can be off by a lot when one of the two trips was delayed so
+/// the median of the latest ones is used.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    /// Server time minus local time in microseconds, oldest first
+    offsets: VecDeque<i64>,
+}
+
+impl ClockSync {
+    /// Adds the answer to a request sent at `sent` and received at `received` (local clock) that
+    /// the server handled at `server_time` (server clock).
+    pub fn add_sample(&mut self, sent: u64, server_time: u64, received: u64) {
+        if received < sent {
+            return;
+        }
+        // The server is assumed to have answered halfway through the round trip
+        let local_time = sent + (received - sent) / 2;
+        if self.offsets.len() >= CLOCK_SYNC_SAMPLES {
+            self.offsets.pop_front();
+        }
+        self.offsets.push_back(server_time as i64 - local_time as i64);
+    }
+
+    pub fn samples(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Microseconds to add to the local clock to get the server's, `None` without samples.
+    pub fn offset(&self) -> Option<i64> {
+        if self.offsets.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<i64> = self.offsets.iter().cloned().collect();
+        sorted.sort_unstable();
+        let mid = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            Some((sorted[mid - 1] + sorted[mid]) / 2)
+        } else {
+            Some(sorted[mid])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock_sync_median() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.offset(), None);
+
+        // Server 1s ahead, 20ms round trips
+        sync.add_sample(0, 1_010_000, 20_000);
+        sync.add_sample(100_000, 1_110_000, 120_000);
+        assert_eq!(sync.offset(), Some(1_000_000));
+
+        // The answer was delayed on the way back, the median ignores it
+        sync.add_sample(200_000, 1_210_000, 500_000);
+        assert_eq!(sync.offset(), Some(1_000_000));
+
+        for i in 0..CLOCK_SYNC_SAMPLES as u64 {
+            sync.add_sample(i * 1000, i * 1000 + 500_000, i * 1000);
+        }
+        assert_eq!(sync.samples(), CLOCK_SYNC_SAMPLES);
+        assert_eq!(sync.offset(), Some(500_000));
+    }
+}
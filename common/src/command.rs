@@ -0,0 +1,122 @@
+use std::fmt;
+
+use serde_derive::{Serialize, Deserialize};
+
+/// Usage and description of every command, in the order `/help` lists them.
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("/nick <name>", "change your nickname"),
+    ("/me <action>", "describe what you're doing"),
+    ("/who", "list the players in your room"),
+    ("/msg <user> <text>", "send a private message"),
+    ("/help", "show this list"),
+];
+
+/// Something typed in the chat, anything that doesn't start with `/` is plain text. A leading
+/// `//` sends a text starting with a single `/`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Say(String),
+    Nick(String),
+    Me(String),
+    Who,
+    Msg { to: String, text: String },
+    Help,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    /// An argument is missing, carries the command's usage
+    Usage(String),
+    NoSuchPlayer(String),
+    /// Players in several other rooms use the nick, none in the sender's
+    AmbiguousPlayer(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "unknown command /{}, try /help", name),
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::NoSuchPlayer(nick) => write!(f, "no player called {}", nick),
+            CommandError::AmbiguousPlayer(nick) => write!(f, "more than one player is called {}, join their room first", nick),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl Command {
+    pub fn parse(input: &str) -> Result<Command, CommandError> {
+        let line = match input.strip_prefix('/') {
+            Some(x) if !x.starts_with('/') => x,
+            Some(x) => return Ok(Command::Say(x.to_string())),
+            None => return Ok(Command::Say(input.to_string())),
+        };
+
+        let (name, args) = split_word(line);
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "nick" => match split_word(args) {
+                ("", _) => Err(usage("/nick")),
+                (nick, _) => Ok(Command::Nick(nick.to_string())),
+            },
+            "me" if args.is_empty() => Err(usage("/me")),
+            "me" => Ok(Command::Me(args.to_string())),
+            "who" => Ok(Command::Who),
+            "msg" => match split_word(args) {
+                ("", _) | (_, "") => Err(usage("/msg")),
+                (to, text) => Ok(Command::Msg { to: to.to_string(), text: text.to_string() }),
+            },
+            "help" => Ok(Command::Help),
+            _ => Err(CommandError::Unknown(name)),
+        }
+    }
+}
+
+/// Text listing every command, one per line.
+pub fn help() -> String {
+    COMMANDS.iter()
+        .map(|(usage, description)| format!("{} - {}", usage, description))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn usage(command: &str) -> CommandError {
+    let usage = COMMANDS.iter()
+        .map(|(usage, _)| *usage)
+        .find(|usage| usage.split(' ').next() == Some(command))
+        .unwrap_or(command);
+    CommandError::Usage(usage.to_string())
+}
+
+/// Splits the first word from the rest, the whitespace between the two is dropped.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_parsing() {
+        assert_eq!(Command::parse("hello /who"), Ok(Command::Say("hello /who".to_string())));
+        assert_eq!(Command::parse("//who"), Ok(Command::Say("/who".to_string())));
+        assert_eq!(Command::parse("/NICK  bob "), Ok(Command::Nick("bob".to_string())));
+        assert_eq!(Command::parse("/me waves  back"), Ok(Command::Me("waves  back".to_string())));
+        assert_eq!(Command::parse("/who"), Ok(Command::Who));
+        assert_eq!(Command::parse("/msg bob hi there"),
+                   Ok(Command::Msg { to: "bob".to_string(), text: "hi there".to_string() }));
+        assert_eq!(Command::parse("/help"), Ok(Command::Help));
+
+        assert_eq!(Command::parse("/msg bob"), Err(CommandError::Usage("/msg <user> <text>".to_string())));
+        assert_eq!(Command::parse("/nick"), Err(CommandError::Usage("/nick <name>".to_string())));
+        assert_eq!(Command::parse("/dance now"), Err(CommandError::Unknown("dance".to_string())));
+        assert_eq!(Command::parse("/"), Err(CommandError::Unknown("".to_string())));
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+/// Both sides send a `Ping` this often, a missing `Pong` is a missed heartbeat.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Smoothed round trip time and its variation, estimated like TCP does (RFC 6298).
+#[derive(Debug, Clone, Default)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    jitter: Duration,
+    last: Option<Duration>,
+}
+
+impl RttEstimator {
+    pub fn add_sample(&mut self, rtt: Duration) {
+        self.last = Some(rtt);
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.jitter = rtt / 2;
+            },
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.jitter = self.jitter * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            },
+        }
+    }
+
+    /// Adds the round trip of a ping sent at `sent` and answered at `now`, both in microseconds.
+    pub fn add_ping(&mut self, sent: u64, now: u64) {
+        // A timestamp from the future can only be garbage
+        if let Some(rtt) = now.checked_sub(sent) {
+            self.add_sample(Duration::from_micros(rtt));
+        }
+    }
+
+    /// Smoothed round trip time, `None` until the first sample.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How much the round trip time usually moves away from the smoothed one.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rtt_smoothing() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rtt(), None);
+
+        rtt.add_ping(1_000, 81_000);
+        assert_eq!(rtt.rtt(), Some(Duration::from_millis(80)));
+        assert_eq!(rtt.jitter(), Duration::from_millis(40));
+
+        // A single spike only moves the estimate by an eighth of the difference
+        rtt.add_sample(Duration::from_millis(160));
+        assert_eq!(rtt.rtt(), Some(Duration::from_millis(90)));
+        assert_eq!(rtt.jitter(), Duration::from_millis(50));
+        assert_eq!(rtt.last(), Some(Duration::from_millis(160)));
+
+        rtt.add_ping(10, 5);
+        assert_eq!(rtt.last(), Some(Duration::from_millis(160)));
+    }
+}
@@ -1,17 +1,163 @@
-
+pub mod clock;
+pub mod command;
+pub mod heartbeat;
+pub mod nick;
+pub mod physics;
+pub mod snapshot;
 
 pub mod protocol {
+    use std::fmt;
+
     use serde_derive::{Serialize, Deserialize};
     use bincode::{deserialize as bin_de, serialize as bin_ser, Error};
 
+    use crate::command::CommandError;
+    use crate::nick::NickError;
+    use crate::physics::{BodyLocation, InputCommand};
+    use crate::snapshot::SnapshotDelta;
+
+    /// Version of the binary protocol, it must be bumped every time the layout of `Message` changes.
+    pub const PROTOCOL_VERSION: u32 = 14;
+
+    /// Biggest binary frame that will be decoded, anything above is rejected without looking at it.
+    pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+    pub type SessionId = u32;
+    /// Identifies an entity shared between server and clients, players use their session id.
+    pub type NetId = u32;
 
+    /// Bincode encodes enum variants by their index so new variants must always be appended
+    /// at the end, otherwise clients with a different version won't even decode the `Hello`.
+    /// Their names go at the end of `MESSAGE_NAMES` too.
     #[derive(Serialize, Deserialize, Debug, PartialEq)]
     pub enum Message {
-        Ping,
-        Pong,
+        /// Timestamp in microseconds on the sender's own clock, the receiver doesn't need to
+        /// understand it and echoes it back unchanged in the `Pong`
+        Ping(u64),
+        Pong(u64),
         Chat(String),
         Nick(String),
-        Me(String)
+        Me(String),
+        Hello { protocol_version: u32, client_name: String },
+        /// `tick_rate` is what turns the server's time into ticks
+        Welcome { session_id: SessionId, server_version: String, tick_rate: u32 },
+        Rejected(RejectReason),
+        ChatFrom { sender: SessionId, nick: String, text: String },
+        MeFrom { sender: SessionId, nick: String, text: String },
+        NickChanged { sender: SessionId, old: String, new: String },
+        /// `ack` is the tick of the last snapshot the client received
+        Input { input: InputCommand, ack: Option<u32> },
+        /// Authoritative location of the receiving player after its input `last_input` was applied,
+        /// `applied` counts the inputs applied since it joined the room, dropped ones are left out
+        PlayerState { last_input: u32, applied: u32, location: BodyLocation },
+        EntitySpawned(EntityState),
+        Snapshot(SnapshotDelta),
+        EntityDespawned(NetId),
+        /// Asks the server's time, `client_time` is in microseconds on the client's clock
+        TimeRequest { client_time: u64 },
+        /// `server_time` is in microseconds since the server's epoch, its tick 0
+        TimeResponse { client_time: u64, server_time: u64 },
+        /// Creates a room and joins it
+        CreateRoom { name: String, max_players: u32 },
+        JoinRoom(String),
+        LeaveRoom,
+        ListRooms,
+        RoomList(Vec<RoomInfo>),
+        /// Everything the client knew about its previous room is gone, entities included
+        RoomJoined(String),
+        RoomLeft,
+        RoomError(RoomError),
+        /// Players in the receiver's room, the answer to `/who`
+        PlayerList(Vec<PlayerInfo>),
+        PrivateFrom { sender: SessionId, nick: String, text: String },
+        CommandError(CommandError),
+        /// Text from the server itself, not from any player
+        Notice(String),
+        /// The nick change was refused, the old nick is kept
+        NickRejected { reason: NickError },
+        /// Sent right before the server closes every connection, `reconnect_after` is how many
+        /// seconds it expects to be away, `None` if it doesn't know
+        ServerShutdown { reason: String, reconnect_after: Option<u32> },
+    }
+
+    /// Names of the `Message` variants in the order bincode numbers them, new variants are
+    /// appended here too.
+    pub const MESSAGE_NAMES: &[&str] = &[
+        "Ping", "Pong", "Chat", "Nick", "Me", "Hello", "Welcome", "Rejected", "ChatFrom", "MeFrom",
+        "NickChanged", "Input", "PlayerState", "EntitySpawned", "Snapshot", "EntityDespawned",
+        "TimeRequest", "TimeResponse", "CreateRoom", "JoinRoom", "LeaveRoom", "ListRooms", "RoomList",
+        "RoomJoined", "RoomLeft", "RoomError", "PlayerList", "PrivateFrom", "CommandError", "Notice",
+        "NickRejected", "ServerShutdown",
+    ];
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    pub enum ModelKind {
+        Cube,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct EntityState {
+        pub id: NetId,
+        pub kind: ModelKind,
+        pub location: BodyLocation,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct RoomInfo {
+        pub name: String,
+        pub players: u32,
+        pub max_players: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct PlayerInfo {
+        pub id: SessionId,
+        pub nick: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub enum RoomError {
+        NotFound,
+        AlreadyExists,
+        Full,
+        InvalidName,
+        NotInRoom,
+        /// Someone in the room has the same nick
+        NickTaken,
+        /// Joining the room the player is already in changes nothing
+        AlreadyInRoom,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub enum RejectReason {
+        VersionMismatch { server_version: u32, client_version: u32 },
+        HandshakeRequired,
+    }
+
+
+    #[derive(Debug)]
+    pub enum ProtocolError {
+        Decode(Error),
+        FrameTooLarge { size: usize, max: usize },
+        UnknownVariant(u32),
+    }
+
+    impl fmt::Display for ProtocolError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ProtocolError::Decode(e) => write!(f, "cannot decode message: {}", e),
+                ProtocolError::FrameTooLarge { size, max } => write!(f, "frame too large: {} bytes (max {})", size, max),
+                ProtocolError::UnknownVariant(tag) => write!(f, "unknown message variant {}", tag),
+            }
+        }
+    }
+
+    impl std::error::Error for ProtocolError {}
+
+    impl From<Error> for ProtocolError {
+        fn from(e: Error) -> Self {
+            ProtocolError::Decode(e)
+        }
     }
 
 
@@ -19,8 +165,26 @@ pub mod protocol {
         bin_ser(&message)
     }
 
-    pub fn deserialize(buffer: &[u8]) -> Result<Message, Error> {
-        bin_de(buffer)
+    /// Index of the variant encoded in `buffer`, read without decoding the rest.
+    pub fn message_tag(buffer: &[u8]) -> Option<u32> {
+        bin_de(buffer).ok()
+    }
+
+    pub fn message_name(buffer: &[u8]) -> Option<&'static str> {
+        MESSAGE_NAMES.get(message_tag(buffer)? as usize).copied()
+    }
+
+    pub fn deserialize(buffer: &[u8]) -> Result<Message, ProtocolError> {
+        if buffer.len() > MAX_FRAME_SIZE {
+            return Err(ProtocolError::FrameTooLarge { size: buffer.len(), max: MAX_FRAME_SIZE });
+        }
+
+        // The tag is the first u32 of the frame, anything past the last variant is from a newer
+        // protocol rather than garbage
+        if let Some(tag) = message_tag(buffer).filter(|&x| x as usize >= MESSAGE_NAMES.len()) {
+            return Err(ProtocolError::UnknownVariant(tag));
+        }
+        Ok(bin_de(buffer)?)
     }
 }
 
@@ -30,9 +194,28 @@ mod test {
 
     #[test]
     fn serde_ping() {
-        let ping = Message::Ping;
+        let ping = Message::Ping(1234);
         let serialized = serialize(ping).unwrap();
         let deserialized = deserialize(&serialized).unwrap();
-        assert_eq!(deserialized, Message::Ping);
+        assert_eq!(deserialized, Message::Ping(1234));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(matches!(deserialize(&[0xFF, 0, 0, 0]), Err(ProtocolError::UnknownVariant(255))));
+        assert!(matches!(deserialize(&[2, 0, 0, 0, 10]), Err(ProtocolError::Decode(_))));
+        // A bad tag in a nested enum is a broken message, not an unknown one
+        assert!(matches!(deserialize(&[7, 0, 0, 0, 9, 0, 0, 0]), Err(ProtocolError::Decode(_))));
+        assert!(matches!(deserialize(&vec![0; MAX_FRAME_SIZE + 1]), Err(ProtocolError::FrameTooLarge { .. })));
+    }
+
+    #[test]
+    fn message_names() {
+        let name = |mex| message_name(&serialize(mex).unwrap());
+        assert_eq!(name(Message::Ping(1)), Some("Ping"));
+        assert_eq!(name(Message::RoomLeft), Some("RoomLeft"));
+        assert_eq!(name(Message::ServerShutdown { reason: String::new(), reconnect_after: None }), Some("ServerShutdown"));
+        assert_eq!(message_name(&[0xFF, 0, 0, 0]), None);
+        assert_eq!(message_name(&[]), None);
     }
 }
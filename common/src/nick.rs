@@ -0,0 +1,97 @@
+use std::fmt;
+
+use serde_derive::{Serialize, Deserialize};
+
+pub const MIN_NICK_LEN: usize = 3;
+pub const MAX_NICK_LEN: usize = 16;
+
+/// Names nobody can take, compared ignoring the case. `guest` followed by a number is reserved
+/// too, it's what the server names players that haven't picked a nick.
+pub const RESERVED_NICKS: &[&str] = &["admin", "administrator", "mod", "moderator", "root", "server", "system"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NickError {
+    TooShort { min: u32 },
+    TooLong { max: u32 },
+    InvalidCharacter(char),
+    Reserved,
+    /// Someone in the same room already has it
+    Taken,
+}
+
+impl fmt::Display for NickError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NickError::TooShort { min } => write!(f, "nickname must be at least {} characters", min),
+            NickError::TooLong { max } => write!(f, "nickname must be at most {} characters", max),
+            NickError::InvalidCharacter(c) => write!(f, "nickname cannot contain {:?}", c),
+            NickError::Reserved => write!(f, "nickname is reserved"),
+            NickError::Taken => write!(f, "nickname is already taken"),
+        }
+    }
+}
+
+impl std::error::Error for NickError {}
+
+/// Letters, digits, `_` and `-`, only ASCII so that different looking names can't be confused.
+pub fn is_nick_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+pub fn is_reserved(nick: &str) -> bool {
+    let lower = nick.to_ascii_lowercase();
+    let guest = lower.strip_prefix("guest")
+        .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()));
+    guest || RESERVED_NICKS.contains(&lower.as_str())
+}
+
+/// Checks everything but the uniqueness, that depends on who else is around.
+pub fn validate(nick: &str) -> Result<(), NickError> {
+    let len = nick.chars().count();
+    if len < MIN_NICK_LEN {
+        return Err(NickError::TooShort { min: MIN_NICK_LEN as u32 });
+    }
+    if len > MAX_NICK_LEN {
+        return Err(NickError::TooLong { max: MAX_NICK_LEN as u32 });
+    }
+    if let Some(c) = nick.chars().find(|&c| !is_nick_char(c)) {
+        return Err(NickError::InvalidCharacter(c));
+    }
+    if is_reserved(nick) {
+        return Err(NickError::Reserved);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nick_length() {
+        assert_eq!(validate(""), Err(NickError::TooShort { min: 3 }));
+        assert_eq!(validate("ab"), Err(NickError::TooShort { min: 3 }));
+        assert_eq!(validate("abc"), Ok(()));
+        assert_eq!(validate("abcdefghijklmnop"), Ok(()));
+        assert_eq!(validate("abcdefghijklmnopq"), Err(NickError::TooLong { max: 16 }));
+    }
+
+    #[test]
+    fn nick_charset() {
+        assert_eq!(validate("Bob_the-2nd"), Ok(()));
+        assert_eq!(validate("bob smith"), Err(NickError::InvalidCharacter(' ')));
+        assert_eq!(validate("bob\n"), Err(NickError::InvalidCharacter('\n')));
+        // Looks like a latin "o" but isn't
+        assert_eq!(validate("bоb"), Err(NickError::InvalidCharacter('о')));
+    }
+
+    #[test]
+    fn nick_reserved() {
+        assert_eq!(validate("admin"), Err(NickError::Reserved));
+        assert_eq!(validate("SeRvEr"), Err(NickError::Reserved));
+        assert_eq!(validate("guest42"), Err(NickError::Reserved));
+        assert_eq!(validate("guest"), Ok(()));
+        assert_eq!(validate("guest4u"), Ok(()));
+        assert_eq!(validate("admins"), Ok(()));
+    }
+}
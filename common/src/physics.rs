@@ -0,0 +1,134 @@
+use bitflags::bitflags;
+use cgmath::prelude::*;
+use cgmath::{Vector3, Matrix4, Deg, Rad};
+use serde_derive::{Serialize, Deserialize};
+use specs::{Component, VecStorage};
+
+/// Distance a player walks in one second.
+pub const PLAYER_SPEED: f32 = 6.0;
+
+/// Inputs are sampled at a fixed rate and each one moves the player by the same step, so the
+/// client can replay them exactly like the server did.
+pub const INPUT_RATE: u32 = 30;
+pub const INPUT_DELTA: f32 = 1.0 / INPUT_RATE as f32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BodyLocation {
+    pub pos: Vector3<f32>,
+    pub yaw: Deg<f32>,
+    pub pitch: Deg<f32>,
+}
+
+impl BodyLocation {
+    pub fn zero() -> BodyLocation {
+        BodyLocation {
+            pos: Vector3::zero(),
+            yaw: Deg::zero(),
+            pitch: Deg::zero(),
+        }
+    }
+
+    pub fn at_pos(pos: Vector3<f32>) -> BodyLocation {
+        BodyLocation {
+            pos,
+            yaw: Deg::zero(),
+            pitch: Deg::zero(),
+        }
+    }
+
+    pub fn rotation_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_angle_x(self.pitch) * Matrix4::from_angle_y(self.yaw)
+    }
+
+    pub fn inv_rotation_matrix(&self) -> Matrix4<f32> {
+        self.rotation_matrix().invert().unwrap()
+    }
+
+    pub fn model_to_world_matrix(&self) -> Matrix4<f32> {
+        self.rotation_matrix() * Matrix4::from_translation(-self.pos)
+    }
+
+    pub fn rotate(&mut self, yaw: Deg<f32>, pitch: Deg<f32>) {
+        self.yaw =  (self.yaw + yaw).normalize();
+        self.pitch = (self.pitch + pitch).normalize();
+    }
+
+    pub fn translate(&mut self, d: Vector3<f32>) {
+        self.pos += d;
+    }
+
+    pub fn forward(&mut self, dir: Vector3<f32>) {
+        let fmax = self.inv_rotation_matrix();
+        let forward = fmax * dir.extend(1.0);
+        self.translate(forward.truncate());
+    }
+
+    pub fn look_at(&mut self, target: Vector3<f32>) {
+        let forward: Vector3<f32> = (target - self.pos).normalize();
+
+        let pitch: Deg<f32> = Rad(forward.y.asin()).into();
+        let yaw: Deg<f32> = Rad(f32::atan2(forward.x, forward.z)).into();
+
+        self.yaw = yaw;
+        self.pitch = pitch;
+    }
+}
+
+
+impl Component for BodyLocation {
+    type Storage = VecStorage<Self>;
+}
+
+
+bitflags! {
+    #[derive(Default)]
+    pub struct MoveDirection: u8 {
+        const FORWARD = 0b0001;
+        const BACK  = 0b0010;
+        const LEFT  = 0b0100;
+        const RIGHT = 0b1000;
+    }
+}
+
+/// What a player wants to do during a single simulation step, the server never takes the
+/// position from the client, only these.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputCommand {
+    pub sequence: u32,
+    pub directions: u8,
+    pub yaw: Deg<f32>,
+    pub pitch: Deg<f32>,
+}
+
+impl InputCommand {
+    pub fn directions(&self) -> MoveDirection {
+        MoveDirection::from_bits_truncate(self.directions)
+    }
+}
+
+/// Moves a player by a single input step, both the server and the client need to run this so it
+/// must only depend on its arguments.
+pub fn apply_input(loc: &mut BodyLocation, input: &InputCommand) {
+    loc.yaw = input.yaw.normalize();
+    loc.pitch = input.pitch.normalize();
+
+    let dirs = input.directions();
+    let mut dir: Vector3<f32> = Vector3::zero();
+
+    if dirs.contains(MoveDirection::FORWARD) {
+        dir.z -= 1.0;
+    }
+    if dirs.contains(MoveDirection::BACK) {
+        dir.z += 1.0;
+    }
+    if dirs.contains(MoveDirection::LEFT) {
+        dir.x -= 1.0;
+    }
+    if dirs.contains(MoveDirection::RIGHT) {
+        dir.x += 1.0;
+    }
+
+    if !dir.is_zero() {
+        loc.forward(dir.normalize() * PLAYER_SPEED * INPUT_DELTA);
+    }
+}
@@ -0,0 +1,217 @@
+use bincode::{deserialize as bin_de, serialize as bin_ser, Error, ErrorKind};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::protocol::{EntityState, ProtocolError};
+
+/// Biggest snapshot a delta can expand to, stops a malicious delta from allocating everything.
+pub const MAX_SNAPSHOT_SIZE: usize = 1024 * 1024;
+
+/// A run header costs more than this many bytes, so shorter unchanged gaps are sent anyway.
+const MIN_GAP: usize = 12;
+
+/// Full state of the networked world at a given tick as seen by a single client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorldSnapshot {
+    pub tick: u32,
+    /// Sorted by id, keeping the order stable is what makes two snapshots look alike byte-wise
+    pub entities: Vec<EntityState>,
+}
+
+impl WorldSnapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        bin_ser(self)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<WorldSnapshot, ProtocolError> {
+        Ok(bin_de(buffer)?)
+    }
+}
+
+/// Bytes that changed after skipping `skip` unchanged ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeltaRun {
+    pub skip: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Encoded snapshot expressed as the differences from a baseline both sides already have.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    /// Tick of the snapshot this delta is built against, `None` when it's built against nothing
+    pub baseline: Option<u32>,
+    pub len: u32,
+    pub runs: Vec<DeltaRun>,
+}
+
+impl SnapshotDelta {
+    /// Builds the delta that turns `baseline` into `full`, bytes past the end of the baseline
+    /// are considered to be zero.
+    pub fn encode(tick: u32, baseline: Option<(u32, &[u8])>, full: &[u8]) -> SnapshotDelta {
+        let base = baseline.map_or(&[][..], |(_, bytes)| bytes);
+        let base_at = |i: usize| base.get(i).cloned().unwrap_or(0);
+
+        let mut runs: Vec<DeltaRun> = Vec::new();
+        // Index right after the last byte already covered by a run
+        let mut written = 0;
+        let mut i = 0;
+
+        while i < full.len() {
+            if full[i] == base_at(i) {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            // Extend the run while the gaps between changes are too short to be worth a new run
+            let mut end = i + 1;
+            let mut j = end;
+            while j < full.len() && j - end < MIN_GAP {
+                if full[j] != base_at(j) {
+                    end = j + 1;
+                }
+                j += 1;
+            }
+
+            runs.push(DeltaRun {
+                skip: (start - written) as u32,
+                bytes: full[start..end].to_vec(),
+            });
+            written = end;
+            i = end;
+        }
+
+        SnapshotDelta {
+            tick,
+            baseline: baseline.map(|(tick, _)| tick),
+            len: full.len() as u32,
+            runs,
+        }
+    }
+
+    /// Rebuilds the full encoded snapshot, `baseline` must be the one referenced by the delta.
+    pub fn apply(&self, baseline: Option<&[u8]>) -> Result<Vec<u8>, ProtocolError> {
+        let len = self.len as usize;
+        if len > MAX_SNAPSHOT_SIZE {
+            return Err(ProtocolError::FrameTooLarge { size: len, max: MAX_SNAPSHOT_SIZE });
+        }
+
+        let base = baseline.unwrap_or(&[]);
+        let mut out = vec![0; len];
+        let common = len.min(base.len());
+        out[..common].copy_from_slice(&base[..common]);
+
+        let mut pos = 0usize;
+        for run in self.runs.iter() {
+            // Both come from the network, a usize is only 32 bits on wasm
+            let range = pos.checked_add(run.skip as usize)
+                .and_then(|start| Some((start, start.checked_add(run.bytes.len())?)))
+                .filter(|&(_, end)| end <= len);
+            let (start, end) = match range {
+                Some(x) => x,
+                None => return Err(ProtocolError::Decode(Box::new(ErrorKind::Custom(format!(
+                    "delta run skipping {} bytes from {} goes past the end of the snapshot ({})",
+                    run.skip, pos, len,
+                ))))),
+            };
+            out[start..end].copy_from_slice(&run.bytes);
+            pos = end;
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::Vector3;
+
+    use crate::physics::BodyLocation;
+    use crate::protocol::{EntityState, ModelKind};
+    use super::*;
+
+    fn snapshot(tick: u32, positions: &[(u32, f32)]) -> WorldSnapshot {
+        WorldSnapshot {
+            tick,
+            entities: positions.iter()
+                .map(|&(id, x)| EntityState {
+                    id,
+                    kind: ModelKind::Cube,
+                    location: BodyLocation::at_pos(Vector3 { x, y: 1.0, z: -2.0 }),
+                })
+                .collect(),
+        }
+    }
+
+    fn roundtrip(baseline: &WorldSnapshot, full: &WorldSnapshot) -> SnapshotDelta {
+        let base_bytes = baseline.to_bytes().unwrap();
+        let full_bytes = full.to_bytes().unwrap();
+
+        let delta = SnapshotDelta::encode(full.tick, Some((baseline.tick, &base_bytes)), &full_bytes);
+        let applied = delta.apply(Some(&base_bytes)).unwrap();
+
+        assert_eq!(applied, full_bytes);
+        assert_eq!(&WorldSnapshot::from_bytes(&applied).unwrap(), full);
+        delta
+    }
+
+    #[test]
+    fn delta_moved_entities() {
+        let base = snapshot(1, &[(1, 0.0), (2, 5.0), (3, 10.0), (4, 15.0)]);
+        let full = snapshot(2, &[(1, 0.0), (2, 5.5), (3, 10.0), (4, 15.0)]);
+
+        let delta = roundtrip(&base, &full);
+        assert_eq!(delta.baseline, Some(1));
+        // Only the tick and a single position changed
+        assert_eq!(delta.runs.len(), 2);
+    }
+
+    #[test]
+    fn delta_unchanged_entities() {
+        let base = snapshot(7, &[(1, 0.0), (2, 5.0)]);
+        let mut full = base.clone();
+        full.tick = 8;
+
+        let delta = roundtrip(&base, &full);
+        assert_eq!(delta.runs.len(), 1);
+    }
+
+    #[test]
+    fn delta_spawned_and_despawned() {
+        let small = snapshot(3, &[(1, 0.0)]);
+        let big = snapshot(4, &[(1, 0.0), (2, 5.0), (3, 10.0)]);
+
+        roundtrip(&small, &big);
+        roundtrip(&big, &small);
+    }
+
+    #[test]
+    fn delta_without_baseline() {
+        let full = snapshot(9, &[(1, 3.0), (2, -4.0)]);
+        let bytes = full.to_bytes().unwrap();
+
+        let delta = SnapshotDelta::encode(full.tick, None, &bytes);
+        assert_eq!(delta.baseline, None);
+        assert_eq!(delta.apply(None).unwrap(), bytes);
+    }
+
+    #[test]
+    fn delta_out_of_bounds() {
+        let delta = SnapshotDelta {
+            tick: 1,
+            baseline: None,
+            len: 4,
+            runs: vec![DeltaRun { skip: 2, bytes: vec![1, 2, 3] }],
+        };
+        assert!(delta.apply(None).is_err());
+
+        // Would wrap around on 32 bits
+        let delta = SnapshotDelta {
+            tick: 1,
+            baseline: None,
+            len: 4,
+            runs: vec![DeltaRun { skip: 2, bytes: vec![1] }, DeltaRun { skip: u32::MAX, bytes: vec![1] }],
+        };
+        assert!(delta.apply(None).is_err());
+    }
+}
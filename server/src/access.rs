@@ -0,0 +1,313 @@
+use std::{fmt, fs, io};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Nick entries are written with this prefix, anything else is an address or a network.
+const NICK_PREFIX: &str = "nick:";
+
+/// Addresses sharing the first `prefix` bits, a single address is a /32 (or a /128).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        ip.is_ipv4() == self.addr.is_ipv4() && mask(ip, self.prefix) == self.addr
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Keeps the first `prefix` bits of the address, zeroing the others.
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    let host_bits = (max_prefix(addr) - prefix) as u32;
+    match addr {
+        IpAddr::V4(x) => IpAddr::V4((u32::from(x) & u32::MAX.checked_shl(host_bits).unwrap_or(0)).into()),
+        IpAddr::V6(x) => IpAddr::V6((u128::from(x) & u128::MAX.checked_shl(host_bits).unwrap_or(0)).into()),
+    }
+}
+
+/// IPv4 clients of a dual stack socket show up as mapped IPv6 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+impl FromStr for Network {
+    type Err = AccessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AccessError::InvalidEntry(s.to_string());
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let addr = canonical(addr.parse().map_err(|_| invalid())?);
+        let prefix = match prefix {
+            Some(x) => x.parse().ok().filter(|&x| x <= max_prefix(addr)).ok_or_else(invalid)?,
+            None => max_prefix(addr),
+        };
+        // Only the network part is kept, 10.1.2.3/8 and 10.0.0.0/8 are the same entry
+        Ok(Network { addr: mask(addr, prefix), prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix == max_prefix(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+/// A single line of the ban or the allow list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Network(Network),
+    Nick(String),
+}
+
+impl Entry {
+    pub fn matches(&self, ip: IpAddr, nick: &str) -> bool {
+        self.matches_ip(ip) || self.matches_nick(nick)
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            Entry::Network(net) => net.contains(ip),
+            Entry::Nick(_) => false,
+        }
+    }
+
+    fn matches_nick(&self, nick: &str) -> bool {
+        match self {
+            Entry::Network(_) => false,
+            Entry::Nick(x) => x.eq_ignore_ascii_case(nick),
+        }
+    }
+}
+
+impl FromStr for Entry {
+    type Err = AccessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_prefix(NICK_PREFIX) {
+            Some("") => Err(AccessError::InvalidEntry(s.to_string())),
+            Some(nick) => Ok(Entry::Nick(nick.to_string())),
+            None => s.parse().map(Entry::Network),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Network(net) => write!(f, "{}", net),
+            Entry::Nick(nick) => write!(f, "{}{}", NICK_PREFIX, nick),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AccessError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    Serialize(toml::ser::Error),
+    InvalidEntry(String),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::Io(path, e) => write!(f, "cannot access {}: {}", path, e),
+            AccessError::Parse(path, e) => write!(f, "invalid access list {}: {}", path, e),
+            AccessError::Serialize(e) => write!(f, "cannot serialize the access list: {}", e),
+            AccessError::InvalidEntry(x) => {
+                write!(f, "invalid entry '{}', expected an address, a network or {}<name>", x, NICK_PREFIX)
+            },
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+/// Layout of the file, entries are kept as strings so that it stays easy to edit by hand.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct AccessFile {
+    ban: Vec<String>,
+    allow: Vec<String>,
+}
+
+/// Who can't connect or use a nick. Allow entries win over ban entries, banning 0.0.0.0/0 and
+/// allowing a few networks only lets those in.
+#[derive(Debug, Default)]
+pub struct AccessList {
+    banned: Vec<Entry>,
+    allowed: Vec<Entry>,
+    /// Where changes are saved, `None` keeps them in memory only
+    path: Option<PathBuf>,
+}
+
+impl AccessList {
+    /// Reads the list from a TOML file, a missing file is an empty list that will be created on
+    /// the first change.
+    pub fn load(path: &Path) -> Result<AccessList, AccessError> {
+        let name = path.display().to_string();
+        let file: AccessFile = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).map_err(|e| AccessError::Parse(name, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => AccessFile::default(),
+            Err(e) => return Err(AccessError::Io(name, e)),
+        };
+
+        let parse = |entries: Vec<String>| entries.iter().map(|x| x.parse()).collect::<Result<Vec<_>, _>>();
+        Ok(AccessList {
+            banned: parse(file.ban)?,
+            allowed: parse(file.allow)?,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Writes the list back to its file, through a temporary one so that a crash can't leave it
+    /// half written.
+    fn save(&self) -> Result<(), AccessError> {
+        let path = match &self.path {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let file = AccessFile {
+            ban: self.banned.iter().map(Entry::to_string).collect(),
+            allow: self.allowed.iter().map(Entry::to_string).collect(),
+        };
+        let content = toml::to_string(&file).map_err(AccessError::Serialize)?;
+
+        let io_error = |e| AccessError::Io(path.display().to_string(), e);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).map_err(io_error)?;
+        fs::rename(&tmp, path).map_err(io_error)
+    }
+
+    pub fn banned(&self) -> &[Entry] {
+        &self.banned
+    }
+
+    pub fn allowed(&self) -> &[Entry] {
+        &self.allowed
+    }
+
+    /// The ban entry that keeps the address out, if any.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), &Entry> {
+        if self.allowed.iter().any(|x| x.matches_ip(ip)) {
+            return Ok(());
+        }
+        self.banned.iter().find(|x| x.matches_ip(ip)).map_or(Ok(()), Err)
+    }
+
+    /// The ban entry that keeps anyone from using the nick, if any.
+    pub fn check_nick(&self, nick: &str) -> Result<(), &Entry> {
+        if self.allowed.iter().any(|x| x.matches_nick(nick)) {
+            return Ok(());
+        }
+        self.banned.iter().find(|x| x.matches_nick(nick)).map_or(Ok(()), Err)
+    }
+
+    /// Adds a ban entry and saves the list, returns false if the entry was already there.
+    pub fn ban(&mut self, entry: Entry) -> Result<bool, AccessError> {
+        let added = insert(&mut self.banned, entry);
+        self.save_if(added)
+    }
+
+    /// Removes a ban entry and saves the list, returns false if there was no such entry.
+    pub fn unban(&mut self, entry: &Entry) -> Result<bool, AccessError> {
+        let removed = remove(&mut self.banned, entry);
+        self.save_if(removed)
+    }
+
+    pub fn allow(&mut self, entry: Entry) -> Result<bool, AccessError> {
+        let added = insert(&mut self.allowed, entry);
+        self.save_if(added)
+    }
+
+    pub fn disallow(&mut self, entry: &Entry) -> Result<bool, AccessError> {
+        let removed = remove(&mut self.allowed, entry);
+        self.save_if(removed)
+    }
+
+    fn save_if(&self, changed: bool) -> Result<bool, AccessError> {
+        if changed {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+}
+
+fn insert(entries: &mut Vec<Entry>, entry: Entry) -> bool {
+    if entries.contains(&entry) {
+        return false;
+    }
+    entries.push(entry);
+    true
+}
+
+fn remove(entries: &mut Vec<Entry>, entry: &Entry) -> bool {
+    let len = entries.len();
+    entries.retain(|x| x != entry);
+    entries.len() != len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(s: &str) -> Entry {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn access_rules() {
+        assert_eq!(entry("10.1.2.3/8"), entry("10.0.0.0/8"));
+        assert_eq!(entry("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(entry("::1/128").to_string(), "::1");
+        assert!("10.0.0.0/33".parse::<Entry>().is_err());
+        assert!("nick:".parse::<Entry>().is_err());
+
+        let mut list = AccessList::default();
+        list.ban(entry("10.0.0.0/8")).unwrap();
+        list.ban(entry("2001:db8::/32")).unwrap();
+        list.ban(entry("nick:Griefer")).unwrap();
+        list.allow(entry("10.0.0.5")).unwrap();
+        assert!(!list.ban(entry("10.0.0.0/8")).unwrap());
+
+        assert_eq!(list.check_ip(ip("10.200.0.1")), Err(&entry("10.0.0.0/8")));
+        assert_eq!(list.check_ip(ip("::ffff:10.0.0.1")), Err(&entry("10.0.0.0/8")));
+        assert_eq!(list.check_ip(ip("10.0.0.5")), Ok(()));
+        assert_eq!(list.check_ip(ip("11.0.0.1")), Ok(()));
+        assert_eq!(list.check_ip(ip("2001:db8:1::1")), Err(&entry("2001:db8::/32")));
+        assert_eq!(list.check_nick("griefer"), Err(&entry("nick:Griefer")));
+        assert_eq!(list.check_nick("player"), Ok(()));
+
+        assert!(list.unban(&entry("10.0.0.0/8")).unwrap());
+        assert_eq!(list.check_ip(ip("10.200.0.1")), Ok(()));
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::{Duration, Instant};
+
+/// Time as the whole server sees it, tick `n` is due `n` tick durations after the epoch.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerClock {
+    epoch: Instant,
+    pub tick_rate: u32,
+}
+
+impl ServerClock {
+    pub fn new(tick_rate: u32) -> ServerClock {
+        ServerClock {
+            epoch: Instant::now(),
+            tick_rate,
+        }
+    }
+
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate
+    }
+
+    /// Microseconds since the epoch, what clients are told when they ask for the time.
+    pub fn now_micros(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
+    pub fn tick_start(&self, tick: u32) -> Instant {
+        self.epoch + self.tick_duration() * tick
+    }
+
+    /// Tick that should be running right now.
+    pub fn current_tick(&self) -> u32 {
+        (self.now_micros() * self.tick_rate as u64 / 1_000_000) as u32
+    }
+}
@@ -0,0 +1,218 @@
+use std::{env, fmt, fs, io};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_derive::Deserialize;
+
+use crate::game::DEFAULT_TICK_RATE;
+use crate::logging::{self, LogFormat};
+
+/// Read when no other config file is given, it's fine for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+const DEFAULT_ACCESS_FILE: &str = "access.toml";
+const DEFAULT_METRICS_PORT: u16 = 9091;
+const DEFAULT_LOG: &str = "info";
+/// Above this the tick would be shorter than a millisecond.
+const MAX_TICK_RATE: u32 = 1000;
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+    -c, --config <FILE>        TOML config file [env: SERVER_CONFIG] [default: server.toml]
+    -a, --address <ADDRESS>    Address to bind to [env: SERVER_ADDRESS] [default: 127.0.0.1]
+    -p, --port <PORT>          Port to listen on [env: SERVER_PORT] [default: 8081]
+    -t, --tick-rate <RATE>     Simulation ticks per second [env: SERVER_TICK_RATE] [default: 30]
+        --access-file <FILE>   Ban and allow list [env: SERVER_ACCESS_FILE] [default: access.toml]
+        --metrics-port <PORT>  Localhost port of the Prometheus metrics, 0 to disable
+                               [env: SERVER_METRICS_PORT] [default: 9091]
+        --log <FILTER>         Log verbosity, a level optionally followed by per-module ones
+                               like info,server::session=debug [env: SERVER_LOG] [default: info]
+        --log-format <FORMAT>  text or json [env: SERVER_LOG_FORMAT] [default: text]
+    -h, --help                 Print this message";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub tick_rate: u32,
+    /// Ban and allow list, created on the first change if it doesn't exist
+    pub access_file: PathBuf,
+    /// Always bound to localhost, 0 turns the metrics off
+    pub metrics_port: u16,
+    /// Default log level followed by per-module ones, in the `RUST_LOG` syntax
+    pub log: String,
+    pub log_format: LogFormat,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8081,
+            tick_rate: DEFAULT_TICK_RATE,
+            access_file: PathBuf::from(DEFAULT_ACCESS_FILE),
+            metrics_port: DEFAULT_METRICS_PORT,
+            log: DEFAULT_LOG.to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    InvalidValue { name: String, value: String },
+    MissingValue(String),
+    UnknownOption(String),
+    /// Not really an error, the usage has to be printed instead of starting the server
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path, e),
+            ConfigError::InvalidValue { name, value } => write!(f, "invalid value for {}: '{}'", name, value),
+            ConfigError::MissingValue(name) => write!(f, "missing value for {}", name),
+            ConfigError::UnknownOption(name) => write!(f, "unknown option {}", name),
+            ConfigError::Help => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        match self.metrics_port {
+            0 => None,
+            port => Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        }
+    }
+
+    /// Builds the config from the defaults, overridden by the config file, then by the
+    /// environment and last by the command line.
+    pub fn load() -> Result<Config, ConfigError> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let flags = parse_flags(&args)?;
+
+        let file = flags.iter()
+            .find(|(name, _)| *name == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env::var("SERVER_CONFIG").ok());
+
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(DEFAULT_CONFIG_FILE)?,
+            None => Config::default(),
+        };
+
+        let vars = [
+            ("address", "SERVER_ADDRESS"),
+            ("port", "SERVER_PORT"),
+            ("tick-rate", "SERVER_TICK_RATE"),
+            ("access-file", "SERVER_ACCESS_FILE"),
+            ("metrics-port", "SERVER_METRICS_PORT"),
+            ("log", "SERVER_LOG"),
+            ("log-format", "SERVER_LOG_FORMAT"),
+        ];
+        for (name, var) in vars {
+            if let Ok(value) = env::var(var) {
+                config.set(name, &value)?;
+            }
+        }
+
+        for (name, value) in flags.iter().filter(|(name, _)| *name != "config") {
+            config.set(name, value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_string(), e))
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        match name {
+            "address" => self.address = parse_value(name, value)?,
+            "port" => self.port = parse_value(name, value)?,
+            "tick-rate" => self.tick_rate = parse_value(name, value)?,
+            "access-file" => self.access_file = PathBuf::from(value),
+            "metrics-port" => self.metrics_port = parse_value(name, value)?,
+            "log" => self.log = value.to_string(),
+            "log-format" => self.log_format = parse_value(name, value)?,
+            _ => return Err(ConfigError::UnknownOption(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.tick_rate == 0 || self.tick_rate > MAX_TICK_RATE {
+            return Err(ConfigError::InvalidValue {
+                name: "tick-rate".to_string(),
+                value: self.tick_rate.to_string(),
+            });
+        }
+        if logging::filter(&self.log).is_none() {
+            return Err(ConfigError::InvalidValue {
+                name: "log".to_string(),
+                value: self.log.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Splits the arguments in (long option name, value) pairs, both `--port 80` and `--port=80`
+/// are accepted.
+fn parse_flags(args: &[String]) -> Result<Vec<(&'static str, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        let name = match flag {
+            "-c" | "--config" => "config",
+            "-a" | "--address" => "address",
+            "-p" | "--port" => "port",
+            "-t" | "--tick-rate" => "tick-rate",
+            "--access-file" => "access-file",
+            "--metrics-port" => "metrics-port",
+            "--log" => "log",
+            "--log-format" => "log-format",
+            "-h" | "--help" => return Err(ConfigError::Help),
+            _ => return Err(ConfigError::UnknownOption(flag.to_string())),
+        };
+
+        let value = match inline.or_else(|| args.next().cloned()) {
+            Some(x) => x,
+            None => return Err(ConfigError::MissingValue(flag.to_string())),
+        };
+        flags.push((name, value));
+    }
+
+    Ok(flags)
+}
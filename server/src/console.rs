@@ -0,0 +1,183 @@
+use std::io::{self, BufRead};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use common::protocol::{Message, SessionId};
+
+use crate::access::{AccessError, Entry};
+use crate::registry::SharedRegistry;
+use crate::session::{encode, Shutdown, CLOSE_POLICY_VIOLATION};
+use crate::stats::Stats;
+
+const HELP: &str = "\
+Commands:
+    list                   List the sessions with their address, nick and round trip time
+    kick <id> [reason]     Disconnect a session
+    ban <entry>            Disconnect everyone matching the entry and refuse them from now on
+    unban <entry>          Remove an entry from the ban list
+    allow <entry>          Let in whoever matches the entry, even if banned
+    disallow <entry>       Remove an entry from the allow list
+    access                 Show the ban and allow lists
+    say <text>             Send a notice to every player
+    stats                  Show the simulation and connection stats
+    shutdown [seconds]     Disconnect everyone and stop the server, telling the players when
+                           to come back if given
+    help                   Print this message
+
+Entries are addresses (10.0.0.1), networks (10.0.0.0/8) or nicknames (nick:name).";
+
+/// What the operator can type on the server's stdin.
+#[derive(Debug)]
+enum Command {
+    List,
+    Kick { id: SessionId, reason: String },
+    Ban(Entry),
+    Unban(Entry),
+    Allow(Entry),
+    Disallow(Entry),
+    Access,
+    Say(String),
+    Stats,
+    /// Seconds before the players should try to reconnect
+    Shutdown(Option<u32>),
+    Help,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, ""),
+        };
+
+        match name {
+            "list" | "ls" => Ok(Command::List),
+            "kick" => {
+                let (id, reason) = match args.find(char::is_whitespace) {
+                    Some(i) => (&args[..i], args[i..].trim_start()),
+                    None => (args, ""),
+                };
+                let id = id.parse().map_err(|_| format!("invalid session id '{}'", id))?;
+                let reason = if reason.is_empty() { "kicked" } else { reason };
+                Ok(Command::Kick { id, reason: reason.to_string() })
+            },
+            "ban" => args.parse().map(Command::Ban).map_err(|e| e.to_string()),
+            "unban" => args.parse().map(Command::Unban).map_err(|e| e.to_string()),
+            "allow" => args.parse().map(Command::Allow).map_err(|e| e.to_string()),
+            "disallow" => args.parse().map(Command::Disallow).map_err(|e| e.to_string()),
+            "access" => Ok(Command::Access),
+            "say" if args.is_empty() => Err("nothing to say".to_string()),
+            "say" => Ok(Command::Say(args.to_string())),
+            "stats" => Ok(Command::Stats),
+            "shutdown" | "quit" if args.is_empty() => Ok(Command::Shutdown(None)),
+            "shutdown" | "quit" => {
+                let seconds = args.parse().map_err(|_| format!("invalid number of seconds '{}'", args))?;
+                Ok(Command::Shutdown(Some(seconds)))
+            },
+            "help" => Ok(Command::Help),
+            _ => Err(format!("unknown command '{}', try help", name)),
+        }
+    }
+}
+
+/// Reads commands from stdin on its own thread, blocking reads have no place on the executor.
+/// `shutdown` is told once the operator asks to stop the server, the console stops reading if
+/// stdin is closed but the server keeps going.
+pub fn spawn(registry: SharedRegistry, stats: Arc<Stats>, shutdown: UnboundedSender<Shutdown>) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match Command::parse(&line) {
+                    Ok(Command::Shutdown(reconnect_after)) => {
+                        let _ = shutdown.send(Shutdown {
+                            reason: "shut down by the operator".to_string(),
+                            reconnect_after,
+                        });
+                        return;
+                    },
+                    Ok(command) => run(command, &registry, &stats),
+                    Err(e) => println!("{}", e),
+                }
+            }
+        })
+        .expect("cannot start console thread")
+}
+
+fn run(command: Command, registry: &SharedRegistry, stats: &Stats) {
+    match command {
+        Command::List => {
+            let registry = registry.lock().unwrap();
+            let sessions = registry.sessions();
+            println!("{} sessions", sessions.len());
+            for (id, session) in sessions {
+                let rtt = session.rtt.map_or("-".to_string(), |x| format!("{} ms", x.as_millis()));
+                println!("{:>6}  {:<21}  {:<16}  {:<12}  {}",
+                         id, session.addr, session.nick, session.room.as_deref().unwrap_or("-"), rtt);
+            }
+        },
+        Command::Kick { id, reason } => {
+            if registry.lock().unwrap().close(id, CLOSE_POLICY_VIOLATION, &reason) {
+                println!("Kicked session {}", id);
+            } else {
+                println!("No session {}", id);
+            }
+        },
+        Command::Ban(entry) => {
+            // Whoever is banned is kicked even if the list can't be saved
+            let (kicked, saved) = registry.lock().unwrap().ban(entry.clone(), CLOSE_POLICY_VIOLATION);
+            println!("Banned {}, {} sessions kicked", entry, kicked);
+            if let Err(e) = saved {
+                println!("Error: {}, the ban only lasts until the server stops", e);
+            }
+        },
+        Command::Unban(entry) => report(registry.lock().unwrap().access_mut().unban(&entry), "Unbanned", &entry),
+        Command::Allow(entry) => report(registry.lock().unwrap().access_mut().allow(entry.clone()), "Allowed", &entry),
+        Command::Disallow(entry) => {
+            report(registry.lock().unwrap().access_mut().disallow(&entry), "Disallowed", &entry)
+        },
+        Command::Access => {
+            let registry = registry.lock().unwrap();
+            let list = |entries: &[Entry]| entries.iter().map(Entry::to_string).collect::<Vec<_>>().join(", ");
+            println!("Banned: {}", list(registry.access().banned()));
+            println!("Allowed: {}", list(registry.access().allowed()));
+        },
+        Command::Say(text) => {
+            registry.lock().unwrap().broadcast(&encode(Message::Notice(text)));
+        },
+        Command::Stats => {
+            println!("{} connections, {} protocol errors, {} messages rate limited",
+                     stats.sessions.load(Ordering::Relaxed),
+                     stats.protocol_errors.load(Ordering::Relaxed),
+                     stats.rate_limited.load(Ordering::Relaxed));
+            for (room, players, ticks) in registry.lock().unwrap().tick_stats() {
+                println!("Room {}: {} players, {} ticks ({} skipped), last {:?}, avg {:?}, max {:?}",
+                         room, players, ticks.ticks, ticks.skipped, ticks.last, ticks.average(), ticks.max);
+            }
+        },
+        Command::Help => println!("{}", HELP),
+        // Handled by the console's loop, it has to stop reading
+        Command::Shutdown(_) => {},
+    }
+}
+
+fn report(result: Result<bool, AccessError>, done: &str, entry: &Entry) {
+    match result {
+        Ok(true) => println!("{} {}", done, entry),
+        Ok(false) => println!("Nothing to do for {}", entry),
+        Err(e) => println!("Error: {}", e),
+    }
+}
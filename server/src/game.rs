@@ -0,0 +1,483 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cgmath::Vector3;
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+
+use common::physics::{apply_input, BodyLocation, InputCommand, INPUT_RATE};
+use common::protocol::{self, EntityState, ModelKind, NetId, SessionId};
+use common::snapshot::{SnapshotDelta, WorldSnapshot};
+
+use crate::clock::ServerClock;
+use crate::session::{encode, Outbox};
+
+pub const DEFAULT_TICK_RATE: u32 = 30;
+
+/// Inputs that can always wait for the next tick, slow tick rates queue the ones sent between
+/// two ticks on top. Anything beyond is dropped to stop speed hacks.
+const MIN_QUEUED_INPUTS: usize = 8;
+/// Snapshots kept to be used as delta baselines, a client acking anything older gets a full one.
+const SNAPSHOT_HISTORY: usize = 32;
+/// Ticks of unused input credit a player can save up, enough to catch up after some jitter.
+const MAX_INPUT_CREDIT_TICKS: f32 = 2.0;
+
+/// Everything the connection tasks can ask to the simulation, applied at the start of each tick.
+pub enum GameEvent {
+    Join { session: SessionId, tx: Outbox },
+    /// `next` is the simulation of the room the session is moving to, it's only told about the
+    /// player once this one is done with it so the client never gets the two rooms mixed up
+    Leave { session: SessionId, next: Option<Sender<GameEvent>> },
+    Input { session: SessionId, input: InputCommand, ack: Option<u32> },
+}
+
+pub struct Player {
+    tx: Outbox,
+    inputs: VecDeque<InputCommand>,
+    /// How many queued inputs can still be applied, it grows at the input rate
+    input_credit: f32,
+    last_input: u32,
+    /// Inputs applied since the player joined the room
+    applied: u32,
+    /// Last snapshot the client said it received
+    acked: Option<u32>,
+    /// Encoded snapshots sent to this client, oldest first
+    history: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl Player {
+    fn baseline(&self) -> Option<(u32, &[u8])> {
+        let acked = self.acked?;
+        self.history.iter()
+            .find(|(tick, _)| *tick == acked)
+            .map(|(tick, bytes)| (*tick, bytes.as_slice()))
+    }
+}
+
+impl Component for Player {
+    type Storage = VecStorage<Self>;
+}
+
+/// Entities that every client should know about.
+pub struct Networked {
+    pub id: NetId,
+    pub kind: ModelKind,
+}
+
+impl Component for Networked {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Debug, Default)]
+pub struct Tick(pub u32);
+
+/// Upper bounds of the tick duration histogram's buckets, a 30 Hz tick lasts 33 ms.
+pub const TICK_BUCKETS: [Duration; 8] = [
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+];
+
+/// How long the simulation of a room takes, shared with the admin console and the metrics.
+#[derive(Debug, Clone, Default)]
+pub struct TickStats {
+    pub ticks: u64,
+    /// Ticks skipped because the thread fell behind
+    pub skipped: u64,
+    pub last: Duration,
+    pub max: Duration,
+    pub total: Duration,
+    /// Ticks that took at most the matching `TICK_BUCKETS` bound and more than the previous one
+    pub buckets: [u64; TICK_BUCKETS.len()],
+}
+
+impl TickStats {
+    fn record(&mut self, elapsed: Duration) {
+        self.ticks += 1;
+        self.last = elapsed;
+        self.max = self.max.max(elapsed);
+        self.total += elapsed;
+        if let Some(i) = TICK_BUCKETS.iter().position(|&x| elapsed <= x) {
+            self.buckets[i] += 1;
+        }
+    }
+
+    pub fn average(&self) -> Duration {
+        match self.ticks {
+            0 => Duration::from_secs(0),
+            n => self.total / n as u32,
+        }
+    }
+}
+
+
+pub struct PlayerInputSystem {
+    /// Inputs a client is expected to send for each tick, on average it can't move more than this
+    inputs_per_tick: f32,
+}
+
+impl PlayerInputSystem {
+    pub fn new(tick_rate: u32) -> PlayerInputSystem {
+        PlayerInputSystem {
+            inputs_per_tick: INPUT_RATE as f32 / tick_rate as f32,
+        }
+    }
+
+    /// Credit a player can save up, never less than a whole input or fast tick rates would
+    /// never apply any.
+    fn max_credit(&self) -> f32 {
+        (self.inputs_per_tick * MAX_INPUT_CREDIT_TICKS).max(1.0)
+    }
+
+    /// Inputs worth keeping for the next ticks, as many as the credit can take at least.
+    fn max_queued(&self) -> usize {
+        (self.max_credit().ceil() as usize).max(MIN_QUEUED_INPUTS)
+    }
+}
+
+impl<'a> System<'a> for PlayerInputSystem {
+    type SystemData = (
+        WriteStorage<'a, Player>,
+        WriteStorage<'a, BodyLocation>,
+    );
+
+    fn run(&mut self, (mut players, mut locations): Self::SystemData) {
+        let max_credit = self.max_credit();
+        for (player, loc) in (&mut players, &mut locations).join() {
+            // A client running slightly faster than the server catches up on the late ticks, but
+            // the credit doesn't pile up while it's idle
+            player.input_credit = (player.input_credit + self.inputs_per_tick).min(max_credit);
+            while player.input_credit >= 1.0 {
+                let input = match player.inputs.pop_front() {
+                    Some(x) => x,
+                    None => break,
+                };
+                apply_input(loc, &input);
+                player.last_input = input.sequence;
+                player.applied += 1;
+                player.input_credit -= 1.0;
+            }
+        }
+    }
+}
+
+pub struct PlayerStateSystem;
+
+impl<'a> System<'a> for PlayerStateSystem {
+    type SystemData = (
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, BodyLocation>,
+    );
+
+    fn run(&mut self, (players, locations): Self::SystemData) {
+        for (player, loc) in (&players, &locations).join() {
+            player.tx.send(encode(protocol::Message::PlayerState {
+                last_input: player.last_input,
+                applied: player.applied,
+                location: loc.clone(),
+            }));
+        }
+    }
+}
+
+/// Sends every player a snapshot of the networked entities, delta encoded against the last one
+/// it acknowledged. The player's own entity is left out as it's already covered by `PlayerState`.
+pub struct ReplicationSystem;
+
+impl<'a> System<'a> for ReplicationSystem {
+    type SystemData = (
+        WriteStorage<'a, Player>,
+        ReadStorage<'a, Networked>,
+        ReadStorage<'a, BodyLocation>,
+        Read<'a, Tick>,
+    );
+
+    fn run(&mut self, (mut players, networked, locations, tick): Self::SystemData) {
+        let mut entities: Vec<EntityState> = (&networked, &locations).join()
+            .map(|(net, loc)| EntityState {
+                id: net.id,
+                kind: net.kind,
+                location: loc.clone(),
+            })
+            .collect();
+        entities.sort_by_key(|e| e.id);
+
+        for (player, own) in (&mut players, &networked).join() {
+            let snapshot = WorldSnapshot {
+                tick: tick.0,
+                entities: entities.iter().filter(|e| e.id != own.id).cloned().collect(),
+            };
+            let bytes = snapshot.to_bytes().expect("cannot serialize");
+
+            let delta = SnapshotDelta::encode(tick.0, player.baseline(), &bytes);
+            player.tx.send(encode(protocol::Message::Snapshot(delta)));
+
+            if player.history.len() >= SNAPSHOT_HISTORY {
+                player.history.pop_front();
+            }
+            player.history.push_back((tick.0, bytes));
+        }
+    }
+}
+
+
+/// The authoritative simulation, only the game thread owns it.
+pub struct Game {
+    world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    name: String,
+    players: HashMap<SessionId, Entity>,
+    max_queued_inputs: usize,
+    /// Sessions that left before their join came in, with the room they left for
+    cancelled: HashMap<SessionId, Option<Sender<GameEvent>>>,
+    events: Receiver<GameEvent>,
+}
+
+impl Game {
+    pub fn new(name: String, events: Receiver<GameEvent>, tick_rate: u32) -> Game {
+        let mut world = World::new();
+        world.insert(Tick(0));
+        world.register::<BodyLocation>();
+        world.register::<Player>();
+        world.register::<Networked>();
+
+        let inputs = PlayerInputSystem::new(tick_rate);
+        let max_queued_inputs = inputs.max_queued();
+        let dispatcher = DispatcherBuilder::new()
+            .with(inputs, "player_input", &[])
+            .with(PlayerStateSystem, "player_state", &["player_input"])
+            .with(ReplicationSystem, "replication", &["player_input"])
+            .build();
+
+        Game {
+            world,
+            dispatcher,
+            name,
+            players: HashMap::new(),
+            max_queued_inputs,
+            cancelled: HashMap::new(),
+            events,
+        }
+    }
+
+    pub fn current_tick(&self) -> u32 {
+        self.world.read_resource::<Tick>().0
+    }
+
+    /// Moves the tick counter forward without simulating anything, the next tick will be `tick + 1`.
+    pub fn skip_to(&mut self, tick: u32) {
+        self.world.write_resource::<Tick>().0 = tick;
+    }
+
+    /// Runs a tick, returns false once the room is gone and no more events will come.
+    pub fn tick(&mut self) -> bool {
+        self.world.write_resource::<Tick>().0 += 1;
+
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => self.on_event(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+
+        self.dispatcher.dispatch(&self.world);
+        self.world.maintain();
+        true
+    }
+
+    fn on_event(&mut self, event: GameEvent) {
+        match event {
+            GameEvent::Join { session, tx } => {
+                if let Some(next) = self.cancelled.remove(&session) {
+                    if let Some(next) = next {
+                        let _ = next.send(GameEvent::Join { session, tx });
+                    }
+                    return;
+                }
+                tx.send(encode(protocol::Message::RoomJoined(self.name.clone())));
+
+                let location = BodyLocation::at_pos(Vector3 { x: 0.0, y: 0.0, z: 5.0 });
+                let state = EntityState {
+                    id: session,
+                    kind: ModelKind::Cube,
+                    location: location.clone(),
+                };
+
+                // The newcomer needs to know everyone else, everyone else needs to know the newcomer
+                {
+                    let players = self.world.read_storage::<Player>();
+                    let networked = self.world.read_storage::<Networked>();
+                    let locations = self.world.read_storage::<BodyLocation>();
+                    for (net, loc) in (&networked, &locations).join() {
+                        tx.send(encode(protocol::Message::EntitySpawned(EntityState {
+                            id: net.id,
+                            kind: net.kind,
+                            location: loc.clone(),
+                        })));
+                    }
+                    for player in players.join() {
+                        player.tx.send(encode(protocol::Message::EntitySpawned(state.clone())));
+                    }
+                }
+
+                let entity = self.world.create_entity()
+                    .with(location)
+                    .with(Networked {
+                        id: session,
+                        kind: state.kind,
+                    })
+                    .with(Player {
+                        tx,
+                        inputs: VecDeque::new(),
+                        input_credit: 0.0,
+                        last_input: 0,
+                        applied: 0,
+                        acked: None,
+                        history: VecDeque::new(),
+                    })
+                    .build();
+                self.players.insert(session, entity);
+            },
+            GameEvent::Leave { session, next } => {
+                let entity = match self.players.remove(&session) {
+                    Some(x) => x,
+                    None => {
+                        // Still on its way from the previous room
+                        self.cancelled.insert(session, next);
+                        return;
+                    },
+                };
+
+                let tx = self.world.read_storage::<Player>().get(entity).map(|p| p.tx.clone());
+                let _ = self.world.delete_entity(entity);
+                for player in self.world.read_storage::<Player>().join() {
+                    player.tx.send(encode(protocol::Message::EntityDespawned(session)));
+                }
+
+                if let Some(tx) = tx {
+                    tx.send(encode(protocol::Message::RoomLeft));
+                    if let Some(next) = next {
+                        let _ = next.send(GameEvent::Join { session, tx });
+                    }
+                }
+            },
+            GameEvent::Input { session, input, ack } => {
+                let entity = match self.players.get(&session) {
+                    Some(x) => *x,
+                    None => return,
+                };
+                let current_tick = self.world.read_resource::<Tick>().0;
+                let mut players = self.world.write_storage::<Player>();
+                if let Some(player) = players.get_mut(entity) {
+                    // Acks from the future or going backwards can only come from a broken client
+                    if ack > player.acked && ack.is_some_and(|x| x < current_tick) {
+                        player.acked = ack;
+                    }
+
+                    let last = player.inputs.back().map_or(player.last_input, |x| x.sequence);
+                    let valid = input.yaw.0.is_finite() && input.pitch.0.is_finite();
+                    // Old or duplicated inputs would be applied twice
+                    if valid && input.sequence > last && player.inputs.len() < self.max_queued_inputs {
+                        player.inputs.push_back(input);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Runs the simulation of a room on its own thread, the dispatcher can't be moved between the
+/// executor's threads and a dedicated one also keeps the tick rate steady. The thread stops when
+/// the room drops its event sender.
+pub fn spawn(room: &str, events: Receiver<GameEvent>, clock: ServerClock,
+             stats: Arc<Mutex<TickStats>>) -> thread::JoinHandle<()> {
+    let room = room.to_string();
+    thread::Builder::new()
+        .name(format!("game-{}", room))
+        .spawn(move || {
+            let mut game = Game::new(room.clone(), events, clock.tick_rate);
+            // Rooms created later start from the clock's tick, the ones before weren't missed
+            game.skip_to(clock.current_tick());
+            loop {
+                let next = game.current_tick() + 1;
+                let due = clock.tick_start(next);
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                } else if clock.current_tick() > next {
+                    // We're late, skip the missed ticks instead of running them back to back. The
+                    // tick number still follows the clock as clients use it to tell the time.
+                    let current = clock.current_tick();
+                    stats.lock().unwrap().skipped += (current - next) as u64;
+                    game.skip_to(current - 1);
+                }
+
+                let start = Instant::now();
+                if !game.tick() {
+                    break;
+                }
+                stats.lock().unwrap().record(start.elapsed());
+            }
+        })
+        .expect("cannot start game thread")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use cgmath::Deg;
+    use common::physics::MoveDirection;
+    use tokio::sync::mpsc::{self, unbounded_channel};
+
+    use super::*;
+
+    /// Runs a second of simulation with a client walking forward at the input rate, returns
+    /// whether the player ended up where all of its inputs lead.
+    fn all_inputs_applied(tick_rate: u32) -> bool {
+        let (events, rx) = channel();
+        let mut game = Game::new("test".to_string(), rx, tick_rate);
+        // Nobody reads what's sent, it only has to fit
+        let (tx, _rx) = mpsc::channel(1024);
+        let (control, _control_rx) = unbounded_channel();
+        events.send(GameEvent::Join { session: 1, tx: Outbox::new(tx, control) }).unwrap();
+        game.tick();
+
+        let mut expected = BodyLocation::at_pos(Vector3 { x: 0.0, y: 0.0, z: 5.0 });
+        let mut sent = 0;
+        for tick in 1..=tick_rate {
+            while sent < INPUT_RATE * tick / tick_rate {
+                sent += 1;
+                let input = InputCommand {
+                    sequence: sent,
+                    directions: MoveDirection::FORWARD.bits(),
+                    yaw: Deg(0.0),
+                    pitch: Deg(0.0),
+                };
+                apply_input(&mut expected, &input);
+                events.send(GameEvent::Input { session: 1, input, ack: None }).unwrap();
+            }
+            game.tick();
+        }
+
+        let entity = game.players[&1];
+        let location = game.world.read_storage::<BodyLocation>().get(entity).unwrap().clone();
+        location == expected
+    }
+
+    #[test]
+    fn input_rate() {
+        for &tick_rate in &[30, 120, 2] {
+            assert!(all_inputs_applied(tick_rate), "inputs lost at {} Hz", tick_rate);
+        }
+    }
+}
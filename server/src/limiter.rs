@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use common::command::Command;
+use common::physics::INPUT_RATE;
+use common::protocol::Message;
+
+/// Dropped messages a client is forgiven, it gets one back every second. A client that runs out
+/// is flooding on purpose and gets kicked.
+const TOLERANCE: f64 = 20.0;
+const TOLERANCE_REFILL: f64 = 1.0;
+
+/// Messages grouped by how often a well-behaved client sends them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Chat,
+    Nick,
+    Input,
+    /// Heartbeats and clock synchronization
+    Control,
+    Other,
+}
+
+impl MessageKind {
+    pub fn of(mex: &Message) -> MessageKind {
+        match mex {
+            // A nick change typed in the chat is still a nick change
+            Message::Chat(text) if matches!(Command::parse(text), Ok(Command::Nick(_))) => MessageKind::Nick,
+            Message::Chat(_) | Message::Me(_) => MessageKind::Chat,
+            Message::Nick(_) => MessageKind::Nick,
+            Message::Input { .. } => MessageKind::Input,
+            Message::Ping(_) | Message::Pong(_) | Message::TimeRequest { .. } => MessageKind::Control,
+            _ => MessageKind::Other,
+        }
+    }
+
+    /// Burst size and messages per second allowed for the kind.
+    fn limit(self) -> (f64, f64) {
+        match self {
+            MessageKind::Chat => (5.0, 1.0),
+            MessageKind::Nick => (3.0, 0.2),
+            // Some slack for clients with a clock running a bit faster than ours
+            MessageKind::Input => (INPUT_RATE as f64, INPUT_RATE as f64 * 1.5),
+            MessageKind::Control => (10.0, 5.0),
+            MessageKind::Other => (10.0, 2.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket holding `capacity` tokens and getting `refill` back every second.
+    pub fn new(capacity: f64, refill: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            refill,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Kick,
+}
+
+/// Limits the messages of a single session, every kind has its own bucket so that spamming the
+/// chat doesn't make the movement stutter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: HashMap<MessageKind, TokenBucket>,
+    tolerance: TokenBucket,
+    pub dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::starting_at(Instant::now())
+    }
+
+    fn starting_at(now: Instant) -> RateLimiter {
+        RateLimiter {
+            buckets: HashMap::new(),
+            tolerance: TokenBucket::new(TOLERANCE, TOLERANCE_REFILL, now),
+            dropped: 0,
+        }
+    }
+
+    pub fn check(&mut self, kind: MessageKind) -> Verdict {
+        self.check_at(kind, Instant::now())
+    }
+
+    fn check_at(&mut self, kind: MessageKind, now: Instant) -> Verdict {
+        let bucket = self.buckets.entry(kind).or_insert_with(|| {
+            let (capacity, refill) = kind.limit();
+            TokenBucket::new(capacity, refill, now)
+        });
+
+        if bucket.try_take(now) {
+            return Verdict::Allow;
+        }
+
+        self.dropped += 1;
+        if self.tolerance.try_take(now) {
+            Verdict::Drop
+        } else {
+            Verdict::Kick
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3.0, 2.0, start);
+        assert!((0..3).all(|_| bucket.try_take(start)));
+        assert!(!bucket.try_take(start));
+
+        // Half a second gives one token back
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // Never more than the capacity, however long it waits
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.try_take(much_later)).count(), 3);
+    }
+
+    #[test]
+    fn drop_then_kick() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::starting_at(start);
+        let (capacity, _) = MessageKind::Chat.limit();
+
+        for _ in 0..capacity as usize {
+            assert_eq!(limiter.check_at(MessageKind::Chat, start), Verdict::Allow);
+        }
+        // Other kinds have their own bucket
+        assert_eq!(limiter.check_at(MessageKind::Input, start), Verdict::Allow);
+
+        for _ in 0..TOLERANCE as usize {
+            assert_eq!(limiter.check_at(MessageKind::Chat, start), Verdict::Drop);
+        }
+        assert_eq!(limiter.check_at(MessageKind::Chat, start), Verdict::Kick);
+        assert_eq!(limiter.dropped, TOLERANCE as u64 + 1);
+
+        // Slowing down earns the tolerance back
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(MessageKind::Chat, later), Verdict::Allow);
+        assert_eq!(limiter.check_at(MessageKind::Chat, later), Verdict::Drop);
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use serde_derive::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// How log lines are written, JSON is one object per line for log shippers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parses the verbosity, it takes the same directives as `RUST_LOG`: a default level followed by
+/// per-module ones, like `info,server::session=debug`.
+pub fn filter(directives: &str) -> Option<EnvFilter> {
+    EnvFilter::try_new(directives).ok()
+}
+
+/// Installs the global logger. Logs go to stderr, stdout is left to the admin console.
+pub fn init(filter: EnvFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stderr().is_terminal())
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
@@ -1,108 +1,276 @@
-extern crate futures;
-extern crate tokio;
-extern crate websocket;
+mod access;
+mod clock;
+mod config;
+mod console;
+mod game;
+mod limiter;
+mod logging;
+mod metrics;
+mod registry;
+mod session;
+mod signal;
+mod stats;
 
 use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use websocket::r#async::Server;
-use websocket::message::{Message, OwnedMessage};
-use websocket::server::InvalidConnection;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::error::{CapacityError, Error as WsError, ProtocolError};
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{debug, error, error_span, info, warn, Instrument};
+use common::heartbeat::HEARTBEAT_INTERVAL;
+use common::protocol::{self, SessionId};
 
-use futures::{future, Future, Sink, Stream};
-use tokio::runtime::TaskExecutor;
-use common::protocol;
-use common::protocol::{deserialize, serialize};
-use common::protocol::Message::Pong;
+use crate::access::AccessList;
+use crate::clock::ServerClock;
+use crate::config::{Config, ConfigError};
+use crate::registry::{Registry, SharedRegistry};
+use crate::session::{encode, next_session_id, Session, SessionEvent, Shutdown, OUTBOX_SIZE, WRITE_TIMEOUT};
+use crate::stats::Stats;
 
-fn main() {
-    let mut runtime = tokio::runtime::Builder::new().build().unwrap();
-    let executor = runtime.executor();
+/// How long the sessions get to say goodbye and flush their queues before the server stops anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROTOCOL: &str = "rust-websocket";
+
+/// What every connection's task needs from the server.
+#[derive(Clone)]
+struct Server {
+    registry: SharedRegistry,
+    stats: Arc<Stats>,
+    clock: ServerClock,
+    /// Set once with the first request to shut down, never marked as seen here so that every
+    /// clone notices it, even the ones made after
+    shutdown: watch::Receiver<Option<Shutdown>>,
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::load() {
+        Ok(x) => x,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return;
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(2);
+        },
+    };
+    // The config made sure the filter is valid
+    logging::init(logging::filter(&config.log).unwrap(), config.log_format);
+
+    let stats = Arc::new(Stats::default());
+    // every room runs its simulation on its own fixed tick, sessions only talk to it through events
+    let clock = ServerClock::new(config.tick_rate);
+    let access = match AccessList::load(&config.access_file) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(2);
+        },
+    };
+    info!(bans = access.banned().len(), exceptions = access.allowed().len(), "Access list loaded");
+    let registry = Arc::new(Mutex::new(Registry::new(clock, access)));
     // bind to the server
-    let server = Server::bind("127.0.0.1:8081", &tokio::reactor::Handle::default()).unwrap();
-
-    // time to build the server's future
-    // this will be a struct containing everything the server is going to do
-
-    // a stream of incoming connections
-    let f = server
-        .incoming()
-        .then(future::ok) // wrap good and bad events into future::ok
-        .filter(|event| {
-            match event {
-                Ok(_) => true, // a good connection
-                Err(InvalidConnection { ref error, .. }) => {
-                    println!("Bad client: {}", error);
-                    false // we want to save the stream if a client cannot make a valid handshake
-                }
-            }
-        })
-        .and_then(|event| event) // unwrap good connections
-        .map_err(|_| ()) // and silently ignore errors (in `.filter`)
-        .for_each(move |(upgrade, addr)| {
-            println!("Got a connection from: {}", addr);
-            // check if it has the protocol we want
-            if !upgrade.protocols().iter().any(|s| s == "rust-websocket") {
-                // reject it if it doesn't
-                spawn_future(upgrade.reject(), "Upgrade Rejection", &executor);
-                return Ok(());
-            }
+    let listener = match TcpListener::bind(config.bind_address()).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(address = %config.bind_address(), error = %e, "Cannot bind the server port");
+            process::exit(2);
+        },
+    };
+    // Port 0 picks a free one, the tests need to know which
+    let address = listener.local_addr().unwrap();
+    info!(%address, tick_rate = config.tick_rate, "Listening");
+
+    if let Some(addr) = config.metrics_address() {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("Serving metrics on http://{}/metrics", addr);
+                spawn_task(metrics::serve(listener, registry.clone(), stats.clone()), "Metrics");
+            },
+            Err(e) => {
+                error!(%addr, error = %e, "Cannot bind the metrics port");
+                process::exit(2);
+            },
+        }
+    }
+
+    let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+    console::spawn(registry.clone(), stats.clone(), shutdown_tx.clone());
+    spawn_task(signal::handle(shutdown_tx), "Signal Handler");
+
+    let (notify_shutdown, shutdown) = watch::channel(None);
+    let server = Server { registry, stats: stats.clone(), clock, shutdown };
 
-            // accept the request to be a ws connection if it does
-            let f = upgrade
-                .use_protocol("rust-websocket")
-                .accept()
-                // send a greeting!
-                .and_then(|(s, _)| s.send(Message::binary(serialize(protocol::Message::Chat(String::from("Hello World!"))).expect("cannot serialize")).into()))
-                // simple echo server impl
-                .and_then(|s| {
-                    let (sink, stream) = s.split();
-                    stream
-                        .take_while(|m| Ok(!m.is_close()))
-                        .filter_map(|m| {
-                            println!("Message from Client: {:?}", m);
-                            match m {
-                                OwnedMessage::Ping(p) => Some(OwnedMessage::Pong(p)),
-                                OwnedMessage::Pong(_) => None,
-                                OwnedMessage::Binary(data) => ws_on_data(data),
-                                _ => Some(m),
-                            }
-                        })
-                        .forward(sink)
-                        .and_then(|(_, sink)| sink.send(OwnedMessage::Close(None)))
-                });
-
-            spawn_future(f, "Client Status", &executor);
-            Ok(())
-        });
-
-    runtime.block_on(f).unwrap();
+    let shutdown = loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    // Everything logged by the connection's tasks carries the session id, the span
+                    // is at the error level so that no verbosity turns it off
+                    let id = next_session_id();
+                    error_span!("session", id, %addr).in_scope(|| {
+                        info!("Got a connection");
+                        spawn_task(handle_connection(stream, addr, id, server.clone()), "Client Status");
+                    });
+                },
+                // we want to keep accepting if a single client cannot connect
+                Err(e) => warn!(error = %e, "Bad client"),
+            },
+            Some(shutdown) = shutdown_rx.recv() => break shutdown,
+        }
+    };
+    info!(reason = %shutdown.reason, "Shutting down");
+    // No new connections from here on
+    drop(listener);
+    let _ = notify_shutdown.send(Some(shutdown));
+
+    // Every session tells its client and closes, then its writer flushes what's left
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    let pending = || stats.sessions.load(Ordering::Relaxed) + stats.writers.load(Ordering::Relaxed);
+    while pending() > 0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    if pending() > 0 {
+        warn!(sessions = stats.sessions.load(Ordering::Relaxed), "Shutdown timed out, dropping the remaining sessions");
+    }
 }
 
-fn ws_on_data(data: Vec<u8>) -> Option<OwnedMessage> {
-    let mex = deserialize(data.as_slice()).expect("cannot deserialize");
+/// Upgrades the connection and runs its session until either side closes it.
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, id: SessionId, server: Server) -> Result<(), WsError> {
+    let check = UpgradeCheck { registry: server.registry.clone(), addr };
+
+    // Frames bigger than the limit are refused as soon as their header arrives
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(protocol::MAX_FRAME_SIZE))
+        .max_frame_size(Some(protocol::MAX_FRAME_SIZE));
+    let mut socket = tokio_tungstenite::accept_hdr_async_with_config(stream, check, Some(config)).await?;
+    // send a greeting!
+    let greeting = encode(protocol::Message::Chat(String::from("Hello World!")));
+    server.stats.record_out(&greeting);
+    socket.send(greeting).await?;
+    let (mut sink, mut stream) = socket.split();
+
+    // everything sent to the client goes through this channel so that other sessions can reach it too
+    let (tx, mut rx) = mpsc::channel::<WsMessage>(OUTBOX_SIZE);
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let writer_control = control_tx.clone();
+    let writer_stats = server.stats.clone();
+    writer_stats.writers.fetch_add(1, Ordering::Relaxed);
+    spawn_task(async move {
+        let mut result = Ok(());
+        while let Some(m) = rx.recv().await {
+            // Rooms can still be sending to a session that just closed, nothing can follow the close frame
+            let close = m.is_close();
+            writer_stats.record_out(&m);
+            // A client that stopped reading would keep the writer, and its socket, around forever
+            let sent = time::timeout(WRITE_TIMEOUT, sink.send(m))
+                .await
+                .unwrap_or_else(|_| Err(WsError::Io(io::ErrorKind::TimedOut.into())));
+            match sent {
+                Ok(()) if !close => {},
+                Ok(()) => break,
+                // The client closed first, what's still queued is of no use to anyone
+                Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => break,
+                Err(WsError::Protocol(ProtocolError::SendAfterClosing)) => break,
+                Err(e) => {
+                    // Nothing else can reach the client, the session has no reason to wait for it
+                    let _ = writer_control.send(SessionEvent::Disconnected);
+                    result = Err(e);
+                    break;
+                },
+            }
+        }
+        writer_stats.writers.fetch_sub(1, Ordering::Relaxed);
+        result
+    }, "Client Writer");
 
-    let response = ws_on_message(mex);
+    let mut session = Session::new(id, addr, tx, control_tx, server.registry, server.stats.clone(), server.clock);
+    let mut heartbeats = time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    let mut shutdown = server.shutdown;
 
-    response.map(|mex| {
-        OwnedMessage::Binary(serialize(mex).expect("cannot serialize"))
-    })
+    loop {
+        let event = tokio::select! {
+            m = stream.next() => match m {
+                Some(Ok(m)) if m.is_close() => SessionEvent::Disconnected,
+                Some(Ok(m)) => {
+                    server.stats.record_in(&m);
+                    SessionEvent::Message(m)
+                },
+                Some(Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size }))) => {
+                    SessionEvent::Error(protocol::ProtocolError::FrameTooLarge { size, max: max_size })
+                },
+                Some(Err(e)) => return Err(e),
+                None => SessionEvent::Disconnected,
+            },
+            _ = heartbeats.tick() => SessionEvent::Heartbeat,
+            // The session keeps a sender itself, this never ends before the session does
+            Some(event) = control_rx.recv() => event,
+            Ok(()) = shutdown.changed() => match shutdown.borrow_and_update().clone() {
+                Some(x) => SessionEvent::Shutdown(x),
+                None => continue,
+            },
+        };
+        if !session.on_event(event) {
+            return Ok(());
+        }
+    }
+}
+
+/// Decides whether the HTTP upgrade goes through, a rejection is the response sent instead.
+struct UpgradeCheck {
+    registry: SharedRegistry,
+    addr: SocketAddr,
 }
 
-fn ws_on_message(mex: protocol::Message) -> Option<protocol::Message> {
-    match mex {
-        protocol::Message::Ping => Some(protocol::Message::Pong),
-        protocol::Message::Pong => None,
-        _ => Some(mex)
+impl Callback for UpgradeCheck {
+    fn on_request(self, request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        // Nobody banned gets past the HTTP handshake, the header is the only way to tell them why
+        if let Err(entry) = self.registry.lock().unwrap().access().check_ip(self.addr.ip()) {
+            info!(%entry, "Rejecting, banned");
+            let mut rejection = ErrorResponse::new(None);
+            *rejection.status_mut() = StatusCode::FORBIDDEN;
+            rejection.headers_mut().insert("X-Rejected-Reason", HeaderValue::from_static("banned"));
+            return Err(rejection);
+        }
+
+        // check if it has the protocol we want
+        let protocols = request.headers().get_all("Sec-WebSocket-Protocol");
+        if !protocols.iter().filter_map(|x| x.to_str().ok()).flat_map(|x| x.split(',')).any(|x| x.trim() == PROTOCOL) {
+            // reject it if it doesn't
+            info!("Rejecting, no {} subprotocol", PROTOCOL);
+            let mut rejection = ErrorResponse::new(None);
+            *rejection.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(rejection);
+        }
+        response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+        Ok(response)
     }
 }
 
-fn spawn_future<F, I, E>(f: F, desc: &'static str, executor: &TaskExecutor)
+fn spawn_task<F, E>(f: F, desc: &'static str)
     where
-        F: Future<Item = I, Error = E> + 'static + Send,
+        F: Future<Output = Result<(), E>> + Send + 'static,
         E: Debug,
 {
-    executor.spawn(
-        f.map_err(move |e| println!("{}: '{:?}'", desc, e))
-            .map(move |_| println!("{}: Finished.", desc)),
-    );
-}
\ No newline at end of file
+    tokio::spawn(async move {
+        match f.await {
+            Ok(()) => debug!(task = desc, "Finished"),
+            Err(e) => warn!(task = desc, error = ?e, "Failed"),
+        }
+    }.in_current_span());
+}
@@ -0,0 +1,168 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::debug;
+
+use crate::game::TICK_BUCKETS;
+use crate::registry::SharedRegistry;
+use crate::stats::Stats;
+
+/// Longest request accepted, only the request line is looked at anyway.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// A scraper that doesn't send its request by then is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers `GET /metrics` with the server's counters in the Prometheus text format. It's a bare
+/// HTTP/1.0 responder, one request per connection, it's meant to be bound to localhost only.
+pub async fn serve(listener: TcpListener, registry: SharedRegistry, stats: Arc<Stats>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let registry = registry.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &registry, &stats).await {
+                debug!(error = %e, "Metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, registry: &SharedRegistry, stats: &Stats) -> io::Result<()> {
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(x) => x?,
+        Err(_) => return Ok(()),
+    };
+    let mut words = request.lines().next().unwrap_or_default().split_whitespace();
+
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(registry, stats)),
+        (Some("GET"), _) => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads up to the end of the headers, the body of a GET is of no interest.
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buffer.windows(4).any(|x| x == b"\r\n\r\n") && buffer.len() < MAX_REQUEST_SIZE {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Formats every metric, one `# HELP` and `# TYPE` header for each.
+fn render(registry: &SharedRegistry, stats: &Stats) -> String {
+    let mut out = String::new();
+    let counter = |x: &AtomicUsize| x.load(Ordering::Relaxed);
+
+    header(&mut out, "server_sessions", "gauge", "Open connections, handshake or not");
+    let _ = writeln!(out, "server_sessions {}", counter(&stats.sessions));
+    header(&mut out, "server_decode_errors_total", "counter", "Messages that couldn't be decoded, oversized ones included");
+    let _ = writeln!(out, "server_decode_errors_total {}", counter(&stats.protocol_errors));
+    header(&mut out, "server_rate_limited_total", "counter", "Messages dropped for going over the rate limits");
+    let _ = writeln!(out, "server_rate_limited_total {}", counter(&stats.rate_limited));
+
+    header(&mut out, "server_bytes_received_total", "counter", "Websocket payload bytes received");
+    let _ = writeln!(out, "server_bytes_received_total {}", stats.bytes_in.load(Ordering::Relaxed));
+    header(&mut out, "server_bytes_sent_total", "counter", "Websocket payload bytes sent");
+    let _ = writeln!(out, "server_bytes_sent_total {}", stats.bytes_out.load(Ordering::Relaxed));
+
+    header(&mut out, "server_messages_received_total", "counter", "Binary messages received by variant");
+    for (name, count) in stats.messages_in.counts() {
+        let _ = writeln!(out, "server_messages_received_total{{variant=\"{}\"}} {}", name, count);
+    }
+    header(&mut out, "server_messages_sent_total", "counter", "Binary messages sent by variant");
+    for (name, count) in stats.messages_out.counts() {
+        let _ = writeln!(out, "server_messages_sent_total{{variant=\"{}\"}} {}", name, count);
+    }
+
+    // Taken in one go so that every room shows up in all of the metrics
+    let rooms = registry.lock().unwrap().tick_stats();
+    header(&mut out, "server_room_players", "gauge", "Players in each room");
+    for (room, players, _) in &rooms {
+        let _ = writeln!(out, "server_room_players{{room=\"{}\"}} {}", escape(room), players);
+    }
+    header(&mut out, "server_ticks_skipped_total", "counter", "Ticks skipped because the simulation fell behind");
+    for (room, _, ticks) in &rooms {
+        let _ = writeln!(out, "server_ticks_skipped_total{{room=\"{}\"}} {}", escape(room), ticks.skipped);
+    }
+    header(&mut out, "server_tick_duration_seconds", "histogram", "Time taken by each simulation tick");
+    for (room, _, ticks) in &rooms {
+        let room = escape(room);
+        let mut cumulative = 0;
+        for (bound, count) in TICK_BUCKETS.iter().zip(&ticks.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "server_tick_duration_seconds_bucket{{room=\"{}\",le=\"{}\"}} {}",
+                             room, bound.as_secs_f64(), cumulative);
+        }
+        let _ = writeln!(out, "server_tick_duration_seconds_bucket{{room=\"{}\",le=\"+Inf\"}} {}", room, ticks.ticks);
+        let _ = writeln!(out, "server_tick_duration_seconds_sum{{room=\"{}\"}} {}", room, ticks.total.as_secs_f64());
+        let _ = writeln!(out, "server_tick_duration_seconds_count{{room=\"{}\"}} {}", room, ticks.ticks);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Label values are quoted, backslashes, quotes and newlines have to be escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use common::protocol::Message;
+
+    use crate::access::AccessList;
+    use crate::clock::ServerClock;
+    use crate::game::DEFAULT_TICK_RATE;
+    use crate::registry::Registry;
+    use crate::session::encode;
+    use super::*;
+
+    #[test]
+    fn metrics_format() {
+        let registry = Arc::new(Mutex::new(Registry::new(ServerClock::new(DEFAULT_TICK_RATE), AccessList::default())));
+        let stats = Stats::default();
+        stats.record_in(&encode(Message::Ping(1)));
+        stats.record_out(&encode(Message::Pong(1)));
+        stats.record_out(&WsMessage::Binary(vec![0xFF, 0, 0, 0].into()));
+
+        let text = render(&registry, &stats);
+        assert!(text.contains("# TYPE server_sessions gauge\nserver_sessions 0\n"));
+        assert!(text.contains("server_bytes_received_total 12\n"));
+        assert!(text.contains("server_bytes_sent_total 16\n"));
+        assert!(text.contains("server_messages_received_total{variant=\"Ping\"} 1\n"));
+        assert!(text.contains("server_messages_received_total{variant=\"Pong\"} 0\n"));
+        assert!(text.contains("server_messages_sent_total{variant=\"Pong\"} 1\n"));
+        assert!(text.contains("server_messages_sent_total{variant=\"unknown\"} 1\n"));
+        assert!(text.contains("server_room_players{room=\"lobby\"} 0\n"));
+        assert!(text.contains("server_tick_duration_seconds_bucket{room=\"lobby\",le=\"0.00025\"}"));
+        assert!(text.contains("server_tick_duration_seconds_bucket{room=\"lobby\",le=\"+Inf\"}"));
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::info;
+
+use common::command::CommandError;
+use common::nick::{self, NickError};
+use common::protocol::{PlayerInfo, RoomError, RoomInfo, SessionId};
+
+use crate::access::{AccessError, AccessList, Entry};
+use crate::clock::ServerClock;
+use crate::game::{self, GameEvent, TickStats};
+use crate::session::{Outbox, SessionEvent};
+
+/// Room every session joins after the handshake, it's never torn down.
+pub const DEFAULT_ROOM: &str = "lobby";
+const DEFAULT_ROOM_PLAYERS: u32 = 32;
+/// Biggest player cap a room can be created with.
+pub const MAX_ROOM_PLAYERS: u32 = 64;
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+pub type SharedRegistry = Arc<Mutex<Registry>>;
+
+/// What the rest of the server knows about a connected session.
+pub struct SessionHandle {
+    pub nick: String,
+    pub addr: SocketAddr,
+    pub tx: Outbox,
+    /// Reaches the session's own task, used to act on its behalf
+    pub control: UnboundedSender<SessionEvent>,
+    pub room: Option<String>,
+    pub rtt: Option<Duration>,
+}
+
+/// A group of sessions sharing chat and a simulation, the game thread stops once `game` is dropped.
+pub struct Room {
+    max_players: u32,
+    members: HashSet<SessionId>,
+    game: Sender<GameEvent>,
+    tick_stats: Arc<Mutex<TickStats>>,
+    permanent: bool,
+}
+
+/// Every session that completed the handshake and every room, used to reach clients other than
+/// the current one.
+pub struct Registry {
+    sessions: HashMap<SessionId, SessionHandle>,
+    rooms: BTreeMap<String, Room>,
+    clock: ServerClock,
+    access: AccessList,
+}
+
+impl Registry {
+    pub fn new(clock: ServerClock, access: AccessList) -> Registry {
+        let mut registry = Registry {
+            sessions: HashMap::new(),
+            rooms: BTreeMap::new(),
+            clock,
+            access,
+        };
+        registry.spawn_room(DEFAULT_ROOM.to_string(), DEFAULT_ROOM_PLAYERS, true);
+        registry
+    }
+
+    pub fn insert(&mut self, id: SessionId, handle: SessionHandle) {
+        self.sessions.insert(id, handle);
+    }
+
+    pub fn remove(&mut self, id: SessionId) -> Option<SessionHandle> {
+        self.leave_room(id);
+        self.sessions.remove(&id)
+    }
+
+    /// Every session sorted by id.
+    pub fn sessions(&self) -> Vec<(SessionId, &SessionHandle)> {
+        let mut sessions: Vec<_> = self.sessions.iter().map(|(id, s)| (*id, s)).collect();
+        sessions.sort_by_key(|(id, _)| *id);
+        sessions
+    }
+
+    pub fn set_rtt(&mut self, id: SessionId, rtt: Option<Duration>) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.rtt = rtt;
+        }
+    }
+
+    pub fn nick(&self, id: SessionId) -> Option<&str> {
+        self.sessions.get(&id).map(|s| s.nick.as_str())
+    }
+
+    /// Changes the nickname of a session, returning the old one. The nick has to be valid and
+    /// nobody else in the session's room can have it.
+    pub fn rename(&mut self, id: SessionId, nick: String) -> Result<String, NickError> {
+        nick::validate(&nick)?;
+        if let Err(entry) = self.access.check_nick(&nick) {
+            info!(%nick, %entry, "Refusing nick, banned");
+            return Err(NickError::Reserved);
+        }
+        if let Some(room) = self.room_of(id) {
+            if self.nick_taken(room, &nick, id) {
+                return Err(NickError::Taken);
+            }
+        }
+
+        let session = self.sessions.get_mut(&id).ok_or(NickError::Taken)?;
+        Ok(std::mem::replace(&mut session.nick, nick))
+    }
+
+    /// Whether anyone in the room but `except` uses the nick, ignoring the case.
+    fn nick_taken(&self, room: &str, nick: &str, except: SessionId) -> bool {
+        self.rooms.get(room).is_some_and(|room| {
+            room.members.iter()
+                .filter(|&&id| id != except)
+                .filter_map(|id| self.sessions.get(id))
+                .any(|s| s.nick.eq_ignore_ascii_case(nick))
+        })
+    }
+
+    /// Finds a session by nickname ignoring the case. Nicks are only unique within a room so
+    /// players in `room` come first, anywhere else the nick must belong to a single player.
+    pub fn find_nick(&self, nick: &str, room: Option<&str>) -> Result<SessionId, CommandError> {
+        let found: Vec<_> = self.sessions.iter()
+            .filter(|(_, s)| s.nick.eq_ignore_ascii_case(nick))
+            .collect();
+        if let Some((id, _)) = found.iter().find(|(_, s)| room.is_some() && s.room.as_deref() == room) {
+            return Ok(**id);
+        }
+        match found.as_slice() {
+            [] => Err(CommandError::NoSuchPlayer(nick.to_string())),
+            [(id, _)] => Ok(**id),
+            _ => Err(CommandError::AmbiguousPlayer(nick.to_string())),
+        }
+    }
+
+    pub fn players(&self, room: &str) -> Vec<PlayerInfo> {
+        let mut players: Vec<_> = self.rooms.get(room)
+            .into_iter()
+            .flat_map(|room| room.members.iter())
+            .filter_map(|id| self.sessions.get(id).map(|s| PlayerInfo { id: *id, nick: s.nick.clone() }))
+            .collect();
+        players.sort_by_key(|x| x.id);
+        players
+    }
+
+    pub fn room_of(&self, id: SessionId) -> Option<&str> {
+        self.sessions.get(&id)?.room.as_deref()
+    }
+
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        self.rooms.iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                players: room.members.len() as u32,
+                max_players: room.max_players,
+            })
+            .collect()
+    }
+
+    pub fn create_room(&mut self, name: &str, max_players: u32) -> Result<(), RoomError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LEN || name.chars().any(char::is_control) {
+            return Err(RoomError::InvalidName);
+        }
+        if self.rooms.contains_key(name) {
+            return Err(RoomError::AlreadyExists);
+        }
+
+        self.spawn_room(name.to_string(), max_players.clamp(1, MAX_ROOM_PLAYERS), false);
+        Ok(())
+    }
+
+    fn spawn_room(&mut self, name: String, max_players: u32, permanent: bool) {
+        let (game_tx, game_rx) = mpsc::channel();
+        let tick_stats = Arc::new(Mutex::new(TickStats::default()));
+        game::spawn(&name, game_rx, self.clock, tick_stats.clone());
+        info!(room = %name, max_players, "Room created");
+        self.rooms.insert(name, Room {
+            max_players,
+            members: HashSet::new(),
+            game: game_tx,
+            tick_stats,
+            permanent,
+        });
+    }
+
+    /// Simulation timings of every room, with its player count.
+    pub fn tick_stats(&self) -> Vec<(String, usize, TickStats)> {
+        self.rooms.iter()
+            .map(|(name, room)| (name.clone(), room.members.len(), room.tick_stats.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// Moves a session to another room, it leaves the one it was in first. The returned sender
+    /// reaches the new room's simulation.
+    pub fn join_room(&mut self, id: SessionId, name: &str) -> Result<Sender<GameEvent>, RoomError> {
+        let name = name.trim();
+        let room = self.rooms.get(name).ok_or(RoomError::NotFound)?;
+        let tx = self.sessions.get(&id).ok_or(RoomError::NotInRoom)?.tx.clone();
+        // A second RoomJoined would make the client forget the room it's still in
+        if room.members.contains(&id) {
+            return Err(RoomError::AlreadyInRoom);
+        }
+        if room.members.len() as u32 >= room.max_players {
+            return Err(RoomError::Full);
+        }
+        let nick = self.nick(id).unwrap_or_default();
+        if self.nick_taken(name, nick, id) {
+            return Err(RoomError::NickTaken);
+        }
+
+        let game = room.game.clone();
+        // The old room hands the player over once it's done with it
+        if self.leave_room_then(id, Some(game.clone())).is_none() {
+            let _ = game.send(GameEvent::Join { session: id, tx });
+        }
+
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.members.insert(id);
+        }
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.room = Some(name.to_string());
+        }
+        Ok(game)
+    }
+
+    /// Takes a session out of its room, the room is torn down if it was the last one in it.
+    pub fn leave_room(&mut self, id: SessionId) -> Option<String> {
+        self.leave_room_then(id, None)
+    }
+
+    fn leave_room_then(&mut self, id: SessionId, next: Option<Sender<GameEvent>>) -> Option<String> {
+        let name = self.sessions.get_mut(&id)?.room.take()?;
+
+        let empty = match self.rooms.get_mut(&name) {
+            Some(room) => {
+                room.members.remove(&id);
+                let _ = room.game.send(GameEvent::Leave { session: id, next });
+                room.members.is_empty() && !room.permanent
+            },
+            None => false,
+        };
+        if empty {
+            info!(room = %name, "Room is empty, tearing it down");
+            self.rooms.remove(&name);
+        }
+
+        Some(name)
+    }
+
+    /// Closes the connection of a session, returns false if there's no such session.
+    pub fn close(&self, id: SessionId, code: u16, reason: &str) -> bool {
+        match self.sessions.get(&id) {
+            Some(session) => {
+                let _ = session.control.send(SessionEvent::Close { code, reason: reason.to_string() });
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn access(&self) -> &AccessList {
+        &self.access
+    }
+
+    /// Lifting bans and allowing anything never closes sessions, adding bans goes through `ban`.
+    pub fn access_mut(&mut self) -> &mut AccessList {
+        &mut self.access
+    }
+
+    /// Adds a ban entry and closes every session it now keeps out, returns how many were closed.
+    /// The ban holds even if the list can't be saved, the error comes along.
+    pub fn ban(&mut self, entry: Entry, code: u16) -> (usize, Result<(), AccessError>) {
+        let saved = self.access.ban(entry.clone()).map(|_| ());
+        // Allow entries can still let some of the matching sessions in
+        let banned: Vec<_> = self.sessions.iter()
+            .filter(|(_, s)| entry.matches(s.addr.ip(), &s.nick))
+            .filter(|(_, s)| self.access.check_ip(s.addr.ip()).is_err() || self.access.check_nick(&s.nick).is_err())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in banned.iter() {
+            self.close(*id, code, "banned");
+        }
+        (banned.len(), saved)
+    }
+
+    /// Sends a message to every session, whatever room it's in.
+    pub fn broadcast(&self, message: &WsMessage) {
+        for session in self.sessions.values() {
+            session.tx.send(message.clone());
+        }
+    }
+
+    pub fn send_to(&self, id: SessionId, message: WsMessage) {
+        if let Some(session) = self.sessions.get(&id) {
+            session.tx.send(message);
+        }
+    }
+
+    /// Sends a message to every session in the room.
+    pub fn broadcast_room(&self, name: &str, message: &WsMessage) {
+        let room = match self.rooms.get(name) {
+            Some(x) => x,
+            None => return,
+        };
+        for session in room.members.iter().filter_map(|id| self.sessions.get(id)) {
+            // The receiver is only gone while the session is shutting down
+            session.tx.send(message.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::{channel, unbounded_channel};
+
+    use crate::game::DEFAULT_TICK_RATE;
+    use super::*;
+
+    fn join(registry: &mut Registry, id: SessionId, room: &str) {
+        let (tx, _) = channel(1);
+        let (control, _) = unbounded_channel();
+        registry.insert(id, SessionHandle {
+            nick: format!("guest{}", id),
+            addr: ([127, 0, 0, 1], 1000 + id as u16).into(),
+            tx: Outbox::new(tx, control.clone()),
+            control,
+            room: None,
+            rtt: None,
+        });
+        assert!(registry.join_room(id, room).is_ok());
+    }
+
+    #[test]
+    fn nick_unique_per_room() {
+        let mut registry = Registry::new(ServerClock::new(DEFAULT_TICK_RATE), AccessList::default());
+        registry.create_room("arena", 8).unwrap();
+        join(&mut registry, 1, DEFAULT_ROOM);
+        join(&mut registry, 2, DEFAULT_ROOM);
+        join(&mut registry, 3, "arena");
+
+        assert_eq!(registry.rename(1, "Bob".to_string()), Ok("guest1".to_string()));
+        assert_eq!(registry.rename(2, "bOB".to_string()), Err(NickError::Taken));
+        // Changing the case of your own nick is fine
+        assert_eq!(registry.rename(1, "BOB".to_string()), Ok("Bob".to_string()));
+        assert_eq!(registry.rename(2, "no way".to_string()), Err(NickError::InvalidCharacter(' ')));
+
+        // Other rooms don't matter, until you try to join them
+        assert_eq!(registry.rename(3, "bob".to_string()), Ok("guest3".to_string()));
+        assert_eq!(registry.join_room(3, DEFAULT_ROOM).err(), Some(RoomError::NickTaken));
+        assert_eq!(registry.room_of(3), Some("arena"));
+        assert_eq!(registry.join_room(3, "arena").err(), Some(RoomError::AlreadyInRoom));
+    }
+
+    #[test]
+    fn ban_without_saving() {
+        let path = std::env::temp_dir().join("server-test-missing-dir").join("access.toml");
+        let access = AccessList::load(&path).unwrap();
+        let mut registry = Registry::new(ServerClock::new(DEFAULT_TICK_RATE), access);
+        join(&mut registry, 1, DEFAULT_ROOM);
+        join(&mut registry, 2, DEFAULT_ROOM);
+
+        // The directory doesn't exist, the session is closed anyway
+        let (kicked, saved) = registry.ban("127.0.0.1".parse().unwrap(), 1008);
+        assert_eq!(kicked, 2);
+        assert!(matches!(saved, Err(AccessError::Io(..))));
+        assert!(registry.access().check_ip([127, 0, 0, 1].into()).is_err());
+    }
+
+    #[test]
+    fn find_nick_across_rooms() {
+        let mut registry = Registry::new(ServerClock::new(DEFAULT_TICK_RATE), AccessList::default());
+        registry.create_room("arena", 8).unwrap();
+        registry.create_room("dungeon", 8).unwrap();
+        join(&mut registry, 1, DEFAULT_ROOM);
+        join(&mut registry, 2, "arena");
+        join(&mut registry, 3, "dungeon");
+        join(&mut registry, 4, "dungeon");
+        registry.rename(2, "bob".to_string()).unwrap();
+        registry.rename(3, "Bob".to_string()).unwrap();
+        registry.rename(4, "carol".to_string()).unwrap();
+
+        // Someone in the sender's own room wins, a single match elsewhere is fine too
+        assert_eq!(registry.find_nick("BOB", Some("dungeon")), Ok(3));
+        assert_eq!(registry.find_nick("carol", Some(DEFAULT_ROOM)), Ok(4));
+        assert_eq!(registry.find_nick("bob", Some(DEFAULT_ROOM)), Err(CommandError::AmbiguousPlayer("bob".to_string())));
+        assert_eq!(registry.find_nick("dave", None), Err(CommandError::NoSuchPlayer("dave".to_string())));
+    }
+}
@@ -0,0 +1,479 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{info, trace, warn};
+
+use common::command::{self, Command};
+use common::heartbeat::RttEstimator;
+use common::protocol::{self, deserialize, serialize, ProtocolError, RejectReason, RoomError, SessionId, PROTOCOL_VERSION};
+
+use crate::clock::ServerClock;
+use crate::game::GameEvent;
+use crate::limiter::{MessageKind, RateLimiter, Verdict};
+use crate::registry::{SessionHandle, SharedRegistry, DEFAULT_ROOM};
+use crate::stats::Stats;
+
+// https://tools.ietf.org/html/rfc6455#section-7.4.1
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Pings the client can leave unanswered before it's considered gone.
+const MAX_MISSED_HEARTBEATS: u32 = 5;
+/// How long a client has to send its `Hello`, answering pings alone doesn't keep it connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages waiting to be written to a client, a few seconds worth of snapshots.
+pub const OUTBOX_SIZE: usize = 256;
+/// A client that doesn't take a message in this long isn't reading anymore.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
+
+pub fn next_session_id() -> SessionId {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Everything that can happen to a connection.
+pub enum SessionEvent {
+    Message(WsMessage),
+    Heartbeat,
+    /// The client sent something that couldn't even be read, the stream can't go on
+    Error(ProtocolError),
+    /// The client closed the connection or the stream ended
+    Disconnected,
+    /// The server wants the client gone, sent by the admin console
+    Close { code: u16, reason: String },
+    Shutdown(Shutdown),
+    /// The outbound queue is full, the client stopped reading
+    Lagging,
+}
+
+/// Where everything sent to a client is queued for its writer. The queue is bounded, a client
+/// that doesn't keep up is dropped instead of having the server buffer for it forever.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::Sender<WsMessage>,
+    control: UnboundedSender<SessionEvent>,
+}
+
+impl Outbox {
+    pub fn new(tx: mpsc::Sender<WsMessage>, control: UnboundedSender<SessionEvent>) -> Outbox {
+        Outbox { tx, control }
+    }
+
+    /// Never blocks, the game threads send through here too.
+    pub fn send(&self, m: WsMessage) {
+        match self.tx.try_send(m) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                let _ = self.control.send(SessionEvent::Lagging);
+            },
+            // If the writer is gone the connection is already dead and there's no one to tell
+            Err(TrySendError::Closed(_)) => {},
+        }
+    }
+}
+
+/// Why the server is going away, every session tells its client before closing.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    pub reason: String,
+    /// Seconds the server expects to be away, if it knows
+    pub reconnect_after: Option<u32>,
+}
+
+/// State of a single client connection, it lives as long as the websocket does.
+pub struct Session {
+    pub id: SessionId,
+    pub addr: SocketAddr,
+    tx: Outbox,
+    control: UnboundedSender<SessionEvent>,
+    registry: SharedRegistry,
+    /// Simulation of the room the session is in
+    game: Option<Sender<GameEvent>>,
+    stats: Arc<Stats>,
+    clock: ServerClock,
+    handshake_done: bool,
+    closed: bool,
+    /// Clock the pings' timestamps are taken from
+    created: Instant,
+    rtt: RttEstimator,
+    missed_heartbeats: u32,
+    limiter: RateLimiter,
+}
+
+impl Session {
+    pub fn new(id: SessionId, addr: SocketAddr, tx: mpsc::Sender<WsMessage>,
+               control: UnboundedSender<SessionEvent>, registry: SharedRegistry, stats: Arc<Stats>,
+               clock: ServerClock) -> Session {
+        stats.sessions.fetch_add(1, Ordering::Relaxed);
+        Session {
+            id,
+            addr,
+            tx: Outbox::new(tx, control.clone()),
+            control,
+            registry,
+            game: None,
+            stats,
+            clock,
+            handshake_done: false,
+            closed: false,
+            created: Instant::now(),
+            rtt: RttEstimator::default(),
+            missed_heartbeats: 0,
+            limiter: RateLimiter::new(),
+        }
+    }
+
+    /// Handles anything that happened to the connection, returns false once it should be dropped.
+    pub fn on_event(&mut self, event: SessionEvent) -> bool {
+        match event {
+            SessionEvent::Message(m) => {
+                trace!("Message from client: {:?}", m);
+                self.on_ws_message(m);
+                true
+            },
+            SessionEvent::Heartbeat => self.on_heartbeat(),
+            SessionEvent::Error(e) => {
+                self.on_protocol_error(e);
+                false
+            },
+            SessionEvent::Disconnected => false,
+            SessionEvent::Close { code, reason } => {
+                if !self.closed {
+                    info!(%reason, "Closed by the server");
+                    self.close(code, reason);
+                }
+                true
+            },
+            SessionEvent::Shutdown(Shutdown { reason, reconnect_after }) => {
+                if !self.closed {
+                    self.send(protocol::Message::ServerShutdown { reason: reason.clone(), reconnect_after });
+                    self.close(CLOSE_GOING_AWAY, reason);
+                }
+                true
+            },
+            SessionEvent::Lagging => {
+                warn!("Outbound queue full, dropping the client");
+                false
+            },
+        }
+    }
+
+    fn on_heartbeat(&mut self) -> bool {
+        if self.closed {
+            // The client had a whole heartbeat to answer the close frame
+            return false;
+        }
+
+        if !self.handshake_done && self.created.elapsed() >= HANDSHAKE_TIMEOUT {
+            info!("No handshake in time, disconnecting");
+            self.reject(RejectReason::HandshakeRequired);
+            return true;
+        }
+
+        if self.missed_heartbeats >= MAX_MISSED_HEARTBEATS {
+            info!(missed = self.missed_heartbeats, "No answer to the last pings, disconnecting");
+            self.close(CLOSE_GOING_AWAY, "heartbeat timeout".to_string());
+            return true;
+        }
+
+        self.missed_heartbeats += 1;
+        self.send(protocol::Message::Ping(self.now()));
+        true
+    }
+
+    fn on_pong(&mut self, sent: u64) {
+        self.missed_heartbeats = 0;
+        self.rtt.add_ping(sent, self.now());
+        if self.handshake_done {
+            self.registry.lock().unwrap().set_rtt(self.id, self.rtt.rtt());
+        }
+    }
+
+    /// Microseconds since the session was created, the timestamp sent in pings.
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_micros() as u64
+    }
+
+    /// Handles a raw websocket message, replies are queued in the session's outbound channel.
+    fn on_ws_message(&mut self, m: WsMessage) {
+        if self.closed {
+            // The close frame has already been queued, ignore everything the client still sends
+            return;
+        }
+
+        match m {
+            // tungstenite answers pings on its own
+            WsMessage::Ping(_) | WsMessage::Pong(_) => {},
+            WsMessage::Binary(data) => self.on_data(&data),
+            _ => {
+                if self.allow(MessageKind::Other) {
+                    self.send_raw(m);
+                }
+            },
+        }
+    }
+
+    fn on_data(&mut self, data: &[u8]) {
+        let mex = match deserialize(data) {
+            Ok(mex) => mex,
+            Err(e) => return self.on_protocol_error(e),
+        };
+
+        if !self.allow(MessageKind::of(&mex)) {
+            return;
+        }
+
+        if !self.handshake_done {
+            return self.on_handshake(mex);
+        }
+
+        self.on_message(mex)
+    }
+
+    fn on_handshake(&mut self, mex: protocol::Message) {
+        match mex {
+            protocol::Message::Hello { protocol_version, client_name } => {
+                if protocol_version != PROTOCOL_VERSION {
+                    info!(%client_name, protocol_version, expected = PROTOCOL_VERSION, "Rejecting, wrong protocol version");
+                    return self.reject(RejectReason::VersionMismatch {
+                        server_version: PROTOCOL_VERSION,
+                        client_version: protocol_version,
+                    });
+                }
+                info!(%client_name, "Handshake completed");
+                self.handshake_done = true;
+                self.registry.lock().unwrap().insert(self.id, SessionHandle {
+                    nick: format!("guest{}", self.id),
+                    addr: self.addr,
+                    tx: self.tx.clone(),
+                    control: self.control.clone(),
+                    room: None,
+                    rtt: self.rtt.rtt(),
+                });
+                self.send(protocol::Message::Welcome {
+                    session_id: self.id,
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                    tick_rate: self.clock.tick_rate,
+                });
+                self.join_room(DEFAULT_ROOM);
+            },
+            // Keepalives are harmless even before the handshake
+            protocol::Message::Ping(sent) => self.send(protocol::Message::Pong(sent)),
+            protocol::Message::Pong(sent) => self.on_pong(sent),
+            _ => self.reject(RejectReason::HandshakeRequired),
+        }
+    }
+
+    fn on_message(&mut self, mex: protocol::Message) {
+        match mex {
+            protocol::Message::Ping(sent) => self.send(protocol::Message::Pong(sent)),
+            protocol::Message::Pong(sent) => self.on_pong(sent),
+            protocol::Message::Chat(text) => match Command::parse(&text) {
+                Ok(command) => self.on_command(command),
+                Err(e) => self.send(protocol::Message::CommandError(e)),
+            },
+            protocol::Message::Me(text) => self.on_command(Command::Me(text)),
+            protocol::Message::Nick(nick) => self.on_command(Command::Nick(nick)),
+            protocol::Message::Input { input, ack } => {
+                if let Some(game) = &self.game {
+                    let _ = game.send(GameEvent::Input { session: self.id, input, ack });
+                }
+            },
+            protocol::Message::CreateRoom { name, max_players } => {
+                let created = self.registry.lock().unwrap().create_room(&name, max_players);
+                match created {
+                    Ok(()) => self.join_room(&name),
+                    Err(e) => self.send(protocol::Message::RoomError(e)),
+                }
+            },
+            protocol::Message::JoinRoom(name) => self.join_room(&name),
+            protocol::Message::LeaveRoom => {
+                self.game = None;
+                if self.registry.lock().unwrap().leave_room(self.id).is_none() {
+                    self.send(protocol::Message::RoomError(RoomError::NotInRoom));
+                }
+            },
+            protocol::Message::ListRooms => {
+                let rooms = self.registry.lock().unwrap().rooms();
+                self.send(protocol::Message::RoomList(rooms));
+            },
+            protocol::Message::TimeRequest { client_time } => {
+                self.send(protocol::Message::TimeResponse { client_time, server_time: self.clock.now_micros() });
+            },
+            _ => self.send(mex),
+        }
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Say(text) => {
+                let nick = self.nick();
+                self.broadcast_room(protocol::Message::ChatFrom { sender: self.id, nick, text });
+            },
+            Command::Me(text) => {
+                let nick = self.nick();
+                self.broadcast_room(protocol::Message::MeFrom { sender: self.id, nick, text });
+            },
+            Command::Nick(nick) => {
+                let renamed = self.registry.lock().unwrap().rename(self.id, nick.clone());
+                match renamed {
+                    Ok(old) => {
+                        info!(%old, new = %nick, "Nick changed");
+                        self.broadcast_room(protocol::Message::NickChanged { sender: self.id, old, new: nick });
+                    },
+                    Err(reason) => self.send(protocol::Message::NickRejected { reason }),
+                }
+            },
+            Command::Who => {
+                let registry = self.registry.lock().unwrap();
+                match registry.room_of(self.id) {
+                    Some(room) => self.send(protocol::Message::PlayerList(registry.players(room))),
+                    None => self.send(protocol::Message::RoomError(RoomError::NotInRoom)),
+                }
+            },
+            Command::Msg { to, text } => {
+                let registry = self.registry.lock().unwrap();
+                match registry.find_nick(&to, registry.room_of(self.id)) {
+                    Ok(id) => {
+                        let nick = registry.nick(self.id).unwrap_or_default().to_string();
+                        registry.send_to(id, encode(protocol::Message::PrivateFrom { sender: self.id, nick, text }));
+                    },
+                    Err(e) => self.send(protocol::Message::CommandError(e)),
+                }
+            },
+            Command::Help => self.send(protocol::Message::Notice(command::help())),
+        }
+    }
+
+    fn nick(&self) -> String {
+        self.registry.lock().unwrap().nick(self.id).unwrap_or_default().to_string()
+    }
+
+    /// Sends a message to everyone in the session's room, the session included.
+    fn broadcast_room(&self, mex: protocol::Message) {
+        let registry = self.registry.lock().unwrap();
+        match registry.room_of(self.id) {
+            Some(room) => registry.broadcast_room(room, &encode(mex)),
+            None => self.send(protocol::Message::RoomError(RoomError::NotInRoom)),
+        }
+    }
+
+    /// The room's simulation confirms the join with `RoomJoined`, errors are sent right away.
+    fn join_room(&mut self, name: &str) {
+        let joined = self.registry.lock().unwrap().join_room(self.id, name);
+        match joined {
+            Ok(game) => self.game = Some(game),
+            Err(e) => self.send(protocol::Message::RoomError(e)),
+        }
+    }
+
+    fn on_protocol_error(&mut self, error: ProtocolError) {
+        warn!(%error, "Protocol error");
+        self.stats.protocol_errors.fetch_add(1, Ordering::Relaxed);
+
+        let code = match error {
+            ProtocolError::Decode(_) => CLOSE_PROTOCOL_ERROR,
+            ProtocolError::FrameTooLarge { .. } => CLOSE_MESSAGE_TOO_BIG,
+            ProtocolError::UnknownVariant(_) => CLOSE_UNSUPPORTED_DATA,
+        };
+        self.close(code, error.to_string());
+    }
+
+    /// Takes a token for a message of the given kind, flooding clients are kicked.
+    fn allow(&mut self, kind: MessageKind) -> bool {
+        match self.limiter.check(kind) {
+            Verdict::Allow => true,
+            Verdict::Drop => {
+                self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                false
+            },
+            Verdict::Kick => {
+                self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                warn!(dropped = self.limiter.dropped, "Kicked for flooding");
+                self.close(CLOSE_POLICY_VIOLATION, "rate limit exceeded".to_string());
+                false
+            },
+        }
+    }
+
+    fn reject(&mut self, reason: RejectReason) {
+        let description = format!("{:?}", reason);
+        self.send(protocol::Message::Rejected(reason));
+        self.close(CLOSE_PROTOCOL_ERROR, description);
+    }
+
+    /// Queues a close frame and stops handling the client's messages, the connection is dropped
+    /// once the client answers with its own close frame.
+    fn close(&mut self, code: u16, reason: String) {
+        self.closed = true;
+        self.leave();
+        self.send_raw(WsMessage::Close(Some(CloseFrame { code: code.into(), reason: reason.into() })));
+    }
+
+    fn leave(&mut self) {
+        if self.handshake_done {
+            self.game = None;
+            self.registry.lock().unwrap().remove(self.id);
+        }
+    }
+
+    fn send(&self, mex: protocol::Message) {
+        self.send_raw(encode(mex));
+    }
+
+    fn send_raw(&self, m: WsMessage) {
+        self.tx.send(m);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stats.sessions.fetch_sub(1, Ordering::Relaxed);
+        if !self.closed {
+            self.send_raw(WsMessage::Close(None));
+            self.leave();
+        }
+    }
+}
+
+pub fn encode(mex: protocol::Message) -> WsMessage {
+    WsMessage::Binary(serialize(mex).expect("cannot serialize").into())
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[test]
+    fn outbox_full() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let (control, mut control_rx) = unbounded_channel();
+        let outbox = Outbox::new(tx, control);
+
+        outbox.send(WsMessage::Ping(Vec::new().into()));
+        outbox.send(WsMessage::Ping(Vec::new().into()));
+        assert!(control_rx.try_recv().is_err());
+        // The client isn't keeping up, the session is told to drop it
+        outbox.send(WsMessage::Ping(Vec::new().into()));
+        assert!(matches!(control_rx.try_recv(), Ok(SessionEvent::Lagging)));
+
+        // Once the writer is gone there's nobody to tell
+        rx.close();
+        outbox.send(WsMessage::Ping(Vec::new().into()));
+        assert!(control_rx.try_recv().is_err());
+    }
+}
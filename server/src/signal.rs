@@ -0,0 +1,69 @@
+use std::io;
+use std::process;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+use crate::session::Shutdown;
+
+/// Asks for a graceful shutdown on the first SIGINT or SIGTERM, a second one stops the server
+/// right away for when the graceful one takes too long. It has to run on the runtime, the
+/// signals are delivered through its driver.
+pub async fn handle(shutdown: UnboundedSender<Shutdown>) -> io::Result<()> {
+    let mut signals = Signals::new()?;
+    let mut received = false;
+    loop {
+        let name = signals.recv().await;
+        if received {
+            warn!("Received {} again, exiting now", name);
+            process::exit(1);
+        }
+        received = true;
+
+        info!("Received {}, shutting down", name);
+        let _ = shutdown.send(Shutdown {
+            reason: "server shutting down".to_string(),
+            reconnect_after: None,
+        });
+    }
+}
+
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> io::Result<Signals> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> io::Result<Signals> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use common::protocol::{message_tag, MESSAGE_NAMES};
+
+/// Counters shared by every connection task.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Open connections, handshake or not
+    pub sessions: AtomicUsize,
+    /// Connections still flushing their outbound queue, they can outlive the session
+    pub writers: AtomicUsize,
+    pub protocol_errors: AtomicUsize,
+    /// Messages dropped for going over the rate limits
+    pub rate_limited: AtomicUsize,
+    /// Payload bytes of every websocket message, control frames included
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub messages_in: MessageCounts,
+    pub messages_out: MessageCounts,
+}
+
+impl Stats {
+    pub fn record_in(&self, m: &WsMessage) {
+        self.bytes_in.fetch_add(m.len() as u64, Ordering::Relaxed);
+        if let WsMessage::Binary(data) = m {
+            self.messages_in.record(data);
+        }
+    }
+
+    pub fn record_out(&self, m: &WsMessage) {
+        self.bytes_out.fetch_add(m.len() as u64, Ordering::Relaxed);
+        if let WsMessage::Binary(data) = m {
+            self.messages_out.record(data);
+        }
+    }
+}
+
+/// Binary messages seen for each `Message` variant, the last slot counts the ones with an
+/// unknown tag.
+#[derive(Debug)]
+pub struct MessageCounts {
+    counts: Vec<AtomicU64>,
+}
+
+impl Default for MessageCounts {
+    fn default() -> Self {
+        MessageCounts {
+            counts: (0..=MESSAGE_NAMES.len()).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl MessageCounts {
+    fn record(&self, data: &[u8]) {
+        let index = message_tag(data)
+            .map(|x| x as usize)
+            .filter(|&x| x < MESSAGE_NAMES.len())
+            .unwrap_or(MESSAGE_NAMES.len());
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every variant with its count, in protocol order, `unknown` last.
+    pub fn counts(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        MESSAGE_NAMES.iter()
+            .chain(std::iter::once(&"unknown"))
+            .zip(&self.counts)
+            .map(|(name, count)| (*name, count.load(Ordering::Relaxed)))
+    }
+}
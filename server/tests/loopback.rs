@@ -0,0 +1,251 @@
+//! Starts the real server on a free port and talks to it over the loopback like a client would.
+
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+use common::protocol::{self, deserialize, serialize, SessionId, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A server process, killed when dropped.
+struct TestServer {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    fn start() -> TestServer {
+        let access_file = std::env::temp_dir().join(format!("server-test-access-{}.toml", std::process::id()));
+        let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--port", "0", "--metrics-port", "0", "--log", "info"])
+            .arg("--access-file").arg(access_file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("cannot start the server");
+
+        // The port is only known once the server logs it
+        let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+        let addr = lines.by_ref()
+            .map(|line| line.expect("cannot read the server's log"))
+            .find_map(|line| {
+                let line = line.split_once("Listening address=")?.1;
+                line.split_whitespace().next()?.parse().ok()
+            })
+            .expect("the server stopped before listening");
+        // Keep reading or the server blocks once the pipe is full
+        thread::spawn(move || lines.for_each(drop));
+
+        TestServer { child, addr }
+    }
+
+    async fn connect(&self) -> Socket {
+        self.connect_with("rust-websocket").await.expect("cannot connect")
+    }
+
+    async fn connect_with(&self, protocol: &'static str) -> Result<Socket, WsError> {
+        let mut request = format!("ws://{}", self.addr).into_client_request().unwrap();
+        if !protocol.is_empty() {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(socket)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+async fn send(socket: &mut Socket, mex: protocol::Message) {
+    send_raw(socket, serialize(mex).unwrap()).await;
+}
+
+async fn send_raw(socket: &mut Socket, data: Vec<u8>) {
+    socket.send(WsMessage::Binary(data.into())).await.expect("cannot send");
+}
+
+async fn next_frame(socket: &mut Socket) -> WsMessage {
+    time::timeout(TIMEOUT, socket.next())
+        .await
+        .expect("timed out waiting for the server")
+        .expect("connection closed")
+        .expect("connection failed")
+}
+
+/// Next protocol message the predicate accepts, everything before it is skipped.
+async fn recv_until(socket: &mut Socket, accept: impl Fn(&protocol::Message) -> bool) -> protocol::Message {
+    loop {
+        if let WsMessage::Binary(data) = next_frame(socket).await {
+            let mex = deserialize(&data).expect("cannot decode the server's message");
+            if accept(&mex) {
+                return mex;
+            }
+        }
+    }
+}
+
+/// Code of the close frame the server sends, skipping anything before it.
+async fn close_code(socket: &mut Socket) -> u16 {
+    loop {
+        if let WsMessage::Close(frame) = next_frame(socket).await {
+            return frame.map_or(0, |x| x.code.into());
+        }
+    }
+}
+
+/// Goes through the greeting and the handshake, returns the session id.
+async fn handshake(socket: &mut Socket) -> SessionId {
+    send(socket, protocol::Message::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: "loopback".to_string(),
+    }).await;
+    match recv_until(socket, |m| matches!(m, protocol::Message::Welcome { .. })).await {
+        protocol::Message::Welcome { session_id, .. } => session_id,
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn greeting() {
+    let server = TestServer::start();
+    let mut socket = server.connect().await;
+
+    let first = recv_until(&mut socket, |_| true).await;
+    assert_eq!(first, protocol::Message::Chat("Hello World!".to_string()));
+}
+
+#[tokio::test]
+async fn ping_pong() {
+    let server = TestServer::start();
+    let mut socket = server.connect().await;
+
+    // Keepalives are answered even before the handshake
+    send(&mut socket, protocol::Message::Ping(1234)).await;
+    let pong = recv_until(&mut socket, |m| matches!(m, protocol::Message::Pong(_))).await;
+    assert_eq!(pong, protocol::Message::Pong(1234));
+
+    handshake(&mut socket).await;
+    send(&mut socket, protocol::Message::Ping(5678)).await;
+    let pong = recv_until(&mut socket, |m| matches!(m, protocol::Message::Pong(_))).await;
+    assert_eq!(pong, protocol::Message::Pong(5678));
+
+    // Websocket pings are answered by the websocket itself
+    socket.send(WsMessage::Ping(b"beat".to_vec().into())).await.unwrap();
+    loop {
+        if let WsMessage::Pong(payload) = next_frame(&mut socket).await {
+            assert_eq!(&payload[..], b"beat");
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn subprotocol_rejection() {
+    let server = TestServer::start();
+
+    for protocol in ["", "some-other-protocol"] {
+        match server.connect_with(protocol).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), 400),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("connected without the rust-websocket subprotocol"),
+        }
+    }
+    // The server is still fine after rejecting
+    let mut socket = server.connect().await;
+    handshake(&mut socket).await;
+}
+
+#[tokio::test]
+async fn malformed_frames() {
+    let server = TestServer::start();
+
+    // Too short for any message
+    let mut socket = server.connect().await;
+    handshake(&mut socket).await;
+    send_raw(&mut socket, vec![0xFF]).await;
+    assert_eq!(close_code(&mut socket).await, 1002);
+
+    let mut socket = server.connect().await;
+    handshake(&mut socket).await;
+    send_raw(&mut socket, vec![0xFF, 0, 0, 0]).await;
+    assert_eq!(close_code(&mut socket).await, 1003);
+
+    // Refused by its header, before the server reads it all
+    let mut socket = server.connect().await;
+    handshake(&mut socket).await;
+    send_raw(&mut socket, vec![0; MAX_FRAME_SIZE + 1]).await;
+    assert_eq!(close_code(&mut socket).await, 1009);
+
+    // Anything but a Hello is refused before the handshake
+    let mut socket = server.connect().await;
+    send(&mut socket, protocol::Message::ListRooms).await;
+    assert_eq!(close_code(&mut socket).await, 1002);
+}
+
+#[tokio::test]
+async fn handshake_timeout() {
+    let server = TestServer::start();
+    let mut socket = server.connect().await;
+
+    // Answering the server's pings isn't enough without a Hello
+    let rejected = time::timeout(TIMEOUT * 2, async {
+        loop {
+            if let WsMessage::Binary(data) = next_frame(&mut socket).await {
+                match deserialize(&data).expect("cannot decode the server's message") {
+                    protocol::Message::Ping(sent) => send(&mut socket, protocol::Message::Pong(sent)).await,
+                    protocol::Message::Rejected(reason) => return reason,
+                    _ => {},
+                }
+            }
+        }
+    }).await.expect("still connected without a handshake");
+    assert_eq!(rejected, protocol::RejectReason::HandshakeRequired);
+    assert_eq!(close_code(&mut socket).await, 1002);
+}
+
+#[tokio::test]
+async fn broadcast() {
+    let server = TestServer::start();
+    let mut alice = server.connect().await;
+    let alice_id = handshake(&mut alice).await;
+    let mut bob = server.connect().await;
+    handshake(&mut bob).await;
+    let mut carol = server.connect().await;
+    let carol_id = handshake(&mut carol).await;
+
+    // Carol moves to a room of her own, she must not hear the lobby
+    send(&mut carol, protocol::Message::CreateRoom { name: "elsewhere".to_string(), max_players: 4 }).await;
+    recv_until(&mut carol, |m| *m == protocol::Message::RoomJoined("elsewhere".to_string())).await;
+
+    send(&mut alice, protocol::Message::Chat("hi all".to_string())).await;
+    for socket in [&mut alice, &mut bob] {
+        let chat = recv_until(socket, |m| matches!(m, protocol::Message::ChatFrom { .. })).await;
+        assert_eq!(chat, protocol::Message::ChatFrom {
+            sender: alice_id,
+            nick: format!("guest{}", alice_id),
+            text: "hi all".to_string(),
+        });
+    }
+
+    send(&mut carol, protocol::Message::Chat("anyone?".to_string())).await;
+    let chat = recv_until(&mut carol, |m| matches!(m, protocol::Message::ChatFrom { .. })).await;
+    assert!(matches!(chat, protocol::Message::ChatFrom { sender, .. } if sender == carol_id));
+}